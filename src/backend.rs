@@ -0,0 +1,107 @@
+use std::fmt;
+
+use async_trait::async_trait;
+use color_eyre::Result;
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    neato::{Account, RobotCmd},
+    neato_types::Robot,
+    settings::Settings,
+};
+
+mod cloud;
+#[cfg(test)]
+pub mod fake;
+mod selected;
+mod signing;
+mod simulator;
+
+pub use cloud::CloudBackend;
+pub use selected::SelectedBackend;
+pub use simulator::SimulatorBackend;
+
+/// What went wrong with a request, decides whether it is worth retrying
+#[derive(Clone, Copy, Deserialize, Serialize, Debug, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum ErrorKind {
+    /// Login failed or the session was rejected
+    Auth,
+    /// The cloud could not reach the robot
+    RobotOffline,
+    /// Too many requests
+    RateLimited,
+    /// The robot refused the command, e.g. with `not_on_charge_base`
+    Rejected,
+    /// Connection problems, timeouts and server errors
+    Transport,
+    Other,
+}
+
+impl ErrorKind {
+    /// Of an error returned by a backend, `Other` unless it is a `RequestError`
+    pub fn of(err: &eyre::Report) -> ErrorKind {
+        err.downcast_ref::<RequestError>()
+            .map_or(ErrorKind::Other, |err| err.kind)
+    }
+
+    /// Whether an idempotent request may be sent again
+    pub fn is_retryable(&self) -> bool {
+        matches!(self, ErrorKind::Transport | ErrorKind::RateLimited)
+    }
+}
+
+impl fmt::Display for ErrorKind {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ErrorKind::Auth => write!(f, "authentication failed"),
+            ErrorKind::RobotOffline => write!(f, "robot offline"),
+            ErrorKind::RateLimited => write!(f, "rate limited"),
+            ErrorKind::Rejected => write!(f, "command rejected"),
+            ErrorKind::Transport => write!(f, "transport error"),
+            ErrorKind::Other => write!(f, "error"),
+        }
+    }
+}
+
+/// A failed request, classified by `ErrorKind`
+#[derive(Debug)]
+pub struct RequestError {
+    pub kind: ErrorKind,
+    pub message: String,
+}
+
+impl RequestError {
+    pub fn new(kind: ErrorKind, message: impl fmt::Display) -> RequestError {
+        RequestError {
+            kind,
+            message: message.to_string(),
+        }
+    }
+}
+
+impl fmt::Display for RequestError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}: {}", self.kind, self.message)
+    }
+}
+
+impl std::error::Error for RequestError {}
+
+/// Where robots are discovered and commands are sent, e.g. the Neato cloud
+#[async_trait]
+pub trait RobotBackend: Send + Sync + 'static {
+    /// Robots on the account, without `id` and `account` set
+    async fn discover_robots(&self, account: &Account) -> Result<Vec<Robot>>;
+
+    /// Sends a command to the robot as request `req_id` and returns the raw response body
+    async fn send_command(&self, robot: &Robot, cmd: &RobotCmd, req_id: &str) -> Result<String>;
+
+    /// Persistent maps of the robot
+    async fn fetch_maps(&self, account: &Account, robot: &Robot) -> Result<serde_json::Value>;
+
+    /// Applies reloaded settings, true if the robots have to be discovered again
+    fn reload(&self, _settings: &Settings) -> bool {
+        false
+    }
+}
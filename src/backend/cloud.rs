@@ -0,0 +1,189 @@
+use async_trait::async_trait;
+use color_eyre::Result;
+use eyre::eyre;
+use log::debug;
+use serde::{Deserialize, Serialize};
+
+use super::{signing::Signer, ErrorKind, RequestError, RobotBackend};
+use crate::{
+    neato::{Account, RobotCmd},
+    neato_types::Robot,
+    secret::Secret,
+};
+
+#[derive(Deserialize)]
+struct SessionsResponse {
+    access_token: Secret<String>,
+}
+
+#[derive(Serialize)]
+struct AuthBody<'a> {
+    email: &'a str,
+    password: &'a str,
+}
+
+const BASE_URL: &str = "https://beehive.neatocloud.com";
+
+/// Classifies a response, `None` if it was successful
+fn classify(status: surf::StatusCode, body: &str) -> Option<ErrorKind> {
+    if body.contains("Robot not online") {
+        return Some(ErrorKind::RobotOffline);
+    }
+    match status {
+        status if status.is_success() => None,
+        surf::StatusCode::Unauthorized | surf::StatusCode::Forbidden => Some(ErrorKind::Auth),
+        surf::StatusCode::TooManyRequests => Some(ErrorKind::RateLimited),
+        status if status.is_server_error() => Some(ErrorKind::Transport),
+        _ => Some(ErrorKind::Other),
+    }
+}
+
+fn request_error(kind: ErrorKind, status: surf::StatusCode, body: &str) -> eyre::Report {
+    RequestError::new(kind, format!("{} {}", status, body.trim())).into()
+}
+
+fn transport_error(err: surf::Error) -> eyre::Report {
+    RequestError::new(ErrorKind::Transport, err).into()
+}
+
+/// Fails with a classified `RequestError` if the response was not successful
+async fn check_status(mut res: surf::Response) -> Result<surf::Response> {
+    if res.status().is_success() {
+        return Ok(res);
+    }
+    let body = res.body_string().await.unwrap_or_default();
+    let kind = classify(res.status(), &body).unwrap_or(ErrorKind::Other);
+    Err(request_error(kind, res.status(), &body))
+}
+
+/// The Neato cloud: Beehive for accounts and robots, Nucleo for robot commands
+#[derive(Default)]
+pub struct CloudBackend {
+    signer: Signer,
+}
+
+impl CloudBackend {
+    async fn login(&self, account: &Account) -> Result<Secret<String>> {
+        let body = AuthBody {
+            email: &account.settings.email,
+            password: account.settings.password.expose(),
+        };
+
+        account.throttle().await;
+        let res = surf::post(&format!("{}/sessions", BASE_URL))
+            .body(surf::Body::from_json(&body).map_err(|err| eyre!(err))?)
+            .await
+            .map_err(transport_error)?;
+        let token = check_status(res)
+            .await?
+            .body_json::<SessionsResponse>()
+            .await
+            .map_err(|err| eyre!(err))?
+            .access_token;
+
+        Ok(token)
+    }
+
+    async fn access_token(&self, account: &Account) -> Result<Secret<String>> {
+        let mut access_token = account.session.lock().await;
+        match access_token.as_ref() {
+            Some(token) => Ok(token.clone()),
+            None => {
+                let token = self.login(account).await?;
+                *access_token = Some(token.clone());
+                Ok(token)
+            }
+        }
+    }
+
+    /// GET request to Beehive, logging in again once if the session has expired
+    async fn beehive_get(&self, account: &Account, path: &str) -> Result<surf::Response> {
+        for _ in 0..2 {
+            let token = self.access_token(account).await?;
+            account.throttle().await;
+            let res = surf::get(&format!("{}{}", BASE_URL, path))
+                .header("Authorization", format!("Bearer {}", token.expose()))
+                .send()
+                .await
+                .map_err(transport_error)?;
+            if res.status() == surf::StatusCode::Unauthorized {
+                debug!(
+                    "Session for account {} expired, logging in again",
+                    account.name()
+                );
+                *account.session.lock().await = None;
+                continue;
+            }
+            return check_status(res).await;
+        }
+
+        Err(RequestError::new(
+            ErrorKind::Auth,
+            format!("Could not log in to Neato account {}", account.name()),
+        )
+        .into())
+    }
+}
+
+#[async_trait]
+impl RobotBackend for CloudBackend {
+    async fn discover_robots(&self, account: &Account) -> Result<Vec<Robot>> {
+        let robots = self
+            .beehive_get(account, "/users/me/robots")
+            .await?
+            // .body_string() // in case you want to debug the whole response
+            .body_json::<Vec<Robot>>()
+            .await
+            .map_err(|err| eyre!(err))?;
+
+        Ok(robots)
+    }
+
+    async fn send_command(&self, robot: &Robot, cmd: &RobotCmd, req_id: &str) -> Result<String> {
+        // https://developers.neatorobotics.com/api/nucleo
+        let robot_message = cmd.build_robot_message(req_id);
+
+        debug!(
+            "Robot name {}, Sending command: {:?}",
+            robot.name, robot_message
+        );
+
+        let signed = self
+            .signer
+            .sign(&robot.serial, &robot.secret_key, &robot_message)?;
+        let mut body = surf::Body::from_bytes(signed.body);
+        body.set_mime(surf::http::mime::JSON);
+
+        let mut res = surf::post(&format!(
+            "{}/vendors/neato/robots/{}/messages",
+            robot.nucleo_url, robot.serial
+        ))
+        .header("Accept", "application/vnd.neato.nucleo.v1")
+        .header("Date", signed.date)
+        .header("Authorization", signed.authorization)
+        .body(body)
+        .await
+        .map_err(transport_error)?;
+        let status = res.status();
+        let result = res.body_string().await.map_err(transport_error)?;
+
+        debug!("response: {}", result);
+
+        if let Some(kind) = classify(status, &result) {
+            return Err(request_error(kind, status, &result));
+        }
+
+        Ok(result)
+    }
+
+    async fn fetch_maps(&self, account: &Account, robot: &Robot) -> Result<serde_json::Value> {
+        let maps = self
+            .beehive_get(account, &format!("/users/me/robots/{}/maps", robot.serial))
+            .await?
+            .body_json::<serde_json::Value>()
+            .await
+            .map_err(|err| eyre!(err))?;
+
+        Ok(maps)
+    }
+}
@@ -0,0 +1,239 @@
+use std::{
+    collections::{HashMap, VecDeque},
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+use async_trait::async_trait;
+use color_eyre::Result;
+use eyre::eyre;
+
+use super::{ErrorKind, RequestError, RobotBackend};
+use crate::{
+    connection::{MqttVersion, Qos},
+    neato::{Account, RobotCmd},
+    neato_types::{NeatoState, Robot, RobotAction, RobotState, RobotStateDetails},
+    secret::Secret,
+    settings::{
+        Backend, EventSettings, HttpSettings, MqttSettings, NeatoSettings, NotificationSettings,
+        QosSettings, RetainSettings, Settings, SimulatorSettings,
+    },
+    topics::{TopicId, TopicStyle},
+};
+
+const STATE: &str = r#"{
+    "reqId": "{req_id}",
+    "alert": null,
+    "error": null,
+    "details": {
+        "isCharging": true,
+        "isDocked": {docked},
+        "isScheduleEnabled": false,
+        "dockHasBeenSeen": false,
+        "charge": 99
+    },
+    "state": {state},
+    "action": 0
+}"#;
+
+/// (state, docked) of a state response
+pub type FakeState = (RobotState, bool);
+
+/// In-memory backend that records the commands sent to its robots
+pub struct FakeBackend {
+    names: Vec<String>,
+    /// Commands sent, as (robot serial, command)
+    pub commands: Arc<Mutex<Vec<(String, RobotCmd)>>>,
+    /// Errors returned by the next requests, in order
+    pub failures: Arc<Mutex<VecDeque<ErrorKind>>>,
+    /// Time each request to the robot with the given serial takes
+    pub delays: Arc<Mutex<HashMap<String, Duration>>>,
+    /// (state, docked) returned by the next state requests of the robot with the given
+    /// serial, docked and idle once they are used up
+    pub states: Arc<Mutex<HashMap<String, VecDeque<FakeState>>>>,
+    /// `result` of the next commands other than state requests, `ok` once they are used up
+    pub results: Arc<Mutex<VecDeque<&'static str>>>,
+}
+
+impl FakeBackend {
+    pub fn new(names: &[&str]) -> FakeBackend {
+        FakeBackend {
+            names: names.iter().map(|n| n.to_string()).collect(),
+            commands: Arc::new(Mutex::new(Vec::new())),
+            failures: Arc::new(Mutex::new(VecDeque::new())),
+            delays: Arc::new(Mutex::new(HashMap::new())),
+            states: Arc::new(Mutex::new(HashMap::new())),
+            results: Arc::new(Mutex::new(VecDeque::new())),
+        }
+    }
+}
+
+pub fn serial(name: &str) -> String {
+    format!("serial-{}", name.to_lowercase())
+}
+
+/// A discovered robot with `name` as its id, docked and idle with a full battery
+pub fn robot(name: &str) -> Robot {
+    let mut robot: Robot = serde_json::from_value(serde_json::json!({
+        "mac_address": "000000000000",
+        "model": "BotVacD7Connected",
+        "name": name,
+        "nucleo_url": "https://nucleo.invalid",
+        "secret_key": "secret",
+        "serial": serial(name),
+        "state": null,
+    }))
+    .unwrap();
+    robot.id = name.to_string();
+    robot.account = String::from("test@example.com");
+    robot.state = Some(state(RobotState::Idle, true, 100));
+    robot
+}
+
+/// State of a robot that is not charging
+pub fn state(state: RobotState, is_docked: bool, charge: i8) -> NeatoState {
+    NeatoState {
+        alert: None,
+        error: None,
+        details: RobotStateDetails {
+            is_charging: false,
+            is_docked,
+            is_schedule_enabled: false,
+            dock_has_been_seen: true,
+            charge,
+        },
+        state,
+        action: RobotAction::Invalid,
+    }
+}
+
+#[async_trait]
+impl RobotBackend for FakeBackend {
+    async fn discover_robots(&self, _account: &Account) -> Result<Vec<Robot>> {
+        let robots = self
+            .names
+            .iter()
+            .map(|name| Robot {
+                id: String::new(),
+                account: String::new(),
+                state: None,
+                ..robot(name)
+            })
+            .collect();
+
+        Ok(robots)
+    }
+
+    async fn send_command(&self, robot: &Robot, cmd: &RobotCmd, req_id: &str) -> Result<String> {
+        self.commands
+            .lock()
+            .unwrap()
+            .push((robot.serial.clone(), cmd.clone()));
+        let delay = self.delays.lock().unwrap().get(&robot.serial).copied();
+        if let Some(delay) = delay {
+            tokio::time::sleep(delay).await;
+        }
+        if let Some(kind) = self.failures.lock().unwrap().pop_front() {
+            return Err(RequestError::new(kind, "fake failure").into());
+        }
+
+        match cmd {
+            RobotCmd::GetRobotState => {
+                let (state, docked) = self
+                    .states
+                    .lock()
+                    .unwrap()
+                    .get_mut(&robot.serial)
+                    .and_then(|states| states.pop_front())
+                    .unwrap_or((RobotState::Idle, true));
+                Ok(STATE
+                    .replace("{req_id}", req_id)
+                    .replace("{docked}", &docked.to_string())
+                    .replace("{state}", &(state as u8).to_string()))
+            }
+            _ => {
+                let result = self.results.lock().unwrap().pop_front().unwrap_or("ok");
+                Ok(serde_json::json!({ "reqId": req_id, "result": result }).to_string())
+            }
+        }
+    }
+
+    async fn fetch_maps(&self, _account: &Account, _robot: &Robot) -> Result<serde_json::Value> {
+        Err(eyre!("The fake backend has no maps"))
+    }
+}
+
+/// Settings with a single account and default topics
+pub fn settings() -> Settings {
+    Settings {
+        neato: NeatoSettings {
+            backend: Backend::Cloud,
+            email: Some(String::from("test@example.com")),
+            password: Some(Secret::new(String::from("password"))),
+            accounts: Vec::new(),
+            request_interval: 0,
+            request_timeout: 10,
+            retries: 2,
+            retry_delay: 0,
+            max_concurrent_requests: 4,
+            poll_interval: 60,
+            offline_poll_interval: 300,
+            cache_timeout: 300,
+            decode_state: false,
+            dry_run: false,
+        },
+        mqtt: MqttSettings {
+            id: String::from("neato-mqtt-test"),
+            host: String::from("localhost"),
+            port: 1883,
+            topic: String::from("home/devices/neato/{id}"),
+            set_topic: String::from("home/devices/neato/{id}/set"),
+            broadcast_topic: None,
+            topic_id: TopicId::Name,
+            topic_style: TopicStyle::Json,
+            version: MqttVersion::V311,
+            state_expiry: 300,
+            keep_alive: 5,
+            clean_session: false,
+            session_expiry: 3600,
+            inflight: 100,
+            channel_capacity: 10,
+            qos: QosSettings {
+                state: Qos::AtMostOnce,
+                commands: Qos::AtLeastOnce,
+                events: Qos::AtMostOnce,
+                discovery: Qos::AtLeastOnce,
+            },
+            retain: RetainSettings {
+                state: false,
+                availability: true,
+                events: false,
+            },
+            aliases: HashMap::new(),
+            publish_request_log: false,
+        },
+        http: HttpSettings {
+            enabled: false,
+            host: String::from("127.0.0.1"),
+            port: 8080,
+        },
+        events: EventSettings {
+            battery_threshold: 20,
+        },
+        notifications: NotificationSettings {
+            topic: String::from("home/devices/neato/{id}/notifications"),
+            cooldown: 3600,
+        },
+        rules: Vec::new(),
+        schedules: Vec::new(),
+        groups: HashMap::new(),
+        simulator: SimulatorSettings {
+            robots: Vec::new(),
+            battery_drain: 1.0,
+            charge_rate: 2.0,
+            docking_time: 30,
+            alert_probability: 0.0,
+            time_scale: 1.0,
+        },
+    }
+}
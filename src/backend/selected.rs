@@ -0,0 +1,91 @@
+use std::sync::{Arc, Mutex, RwLock};
+
+use async_trait::async_trait;
+use color_eyre::Result;
+use log::info;
+
+use super::{CloudBackend, RobotBackend, SimulatorBackend};
+use crate::{
+    neato::{Account, RobotCmd},
+    neato_types::Robot,
+    settings::{Backend, Settings, SimulatorSettings},
+};
+
+fn create(backend: Backend, simulator: &SimulatorSettings) -> Arc<dyn RobotBackend> {
+    match backend {
+        Backend::Cloud => Arc::new(CloudBackend::default()),
+        Backend::Simulator => Arc::new(SimulatorBackend::new(simulator)),
+    }
+}
+
+/// The backend chosen with `neato.backend`, made again when it or the simulator settings
+/// change
+pub struct SelectedBackend {
+    backend: RwLock<Arc<dyn RobotBackend>>,
+    /// Settings the backend was made with
+    settings: Mutex<(Backend, SimulatorSettings)>,
+}
+
+impl SelectedBackend {
+    pub fn new(settings: &Settings) -> SelectedBackend {
+        SelectedBackend {
+            backend: RwLock::new(create(settings.neato.backend, &settings.simulator)),
+            settings: Mutex::new((settings.neato.backend, settings.simulator.clone())),
+        }
+    }
+
+    fn current(&self) -> Arc<dyn RobotBackend> {
+        Arc::clone(&self.backend.read().unwrap())
+    }
+}
+
+#[async_trait]
+impl RobotBackend for SelectedBackend {
+    async fn discover_robots(&self, account: &Account) -> Result<Vec<Robot>> {
+        self.current().discover_robots(account).await
+    }
+
+    async fn send_command(&self, robot: &Robot, cmd: &RobotCmd, req_id: &str) -> Result<String> {
+        self.current().send_command(robot, cmd, req_id).await
+    }
+
+    async fn fetch_maps(&self, account: &Account, robot: &Robot) -> Result<serde_json::Value> {
+        self.current().fetch_maps(account, robot).await
+    }
+
+    fn reload(&self, settings: &Settings) -> bool {
+        let mut current = self.settings.lock().unwrap();
+        let new = (settings.neato.backend, settings.simulator.clone());
+        if *current == new {
+            return false;
+        }
+        info!("Backend settings changed, using the {:?} backend", new.0);
+        *self.backend.write().unwrap() = create(new.0, &new.1);
+        *current = new;
+        true
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::backend::fake;
+
+    #[tokio::test]
+    async fn backend_is_made_again_when_its_settings_change() {
+        let mut settings = fake::settings();
+        settings.neato.backend = Backend::Simulator;
+        settings.simulator.robots = vec![String::from("Vacuum")];
+        let backend = SelectedBackend::new(&settings);
+        let account = Account::new(&settings.neato.accounts()[0], &settings.neato);
+
+        assert!(!backend.reload(&settings));
+        assert_eq!(backend.discover_robots(&account).await.unwrap().len(), 1);
+
+        settings.simulator.robots.push(String::from("Botvac2"));
+        assert!(backend.reload(&settings));
+        let robots = backend.discover_robots(&account).await.unwrap();
+        assert_eq!(robots.len(), 2);
+        assert_eq!(robots[1].name, "Botvac2");
+    }
+}
@@ -0,0 +1,174 @@
+use chrono::{DateTime, Utc};
+use color_eyre::Result;
+use hmac::{Hmac, Mac};
+use serde::Serialize;
+use sha2::Sha256;
+
+use crate::secret::Secret;
+
+type HmacSha256 = Hmac<Sha256>;
+
+/// Source of the `Date` header, replaced by a fixed time in tests
+pub trait Clock: Send + Sync {
+    fn now(&self) -> DateTime<Utc>;
+}
+
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now(&self) -> DateTime<Utc> {
+        Utc::now()
+    }
+}
+
+/// A Nucleo request body together with the headers that authenticate it
+#[derive(Debug)]
+pub struct SignedMessage {
+    /// Exactly the bytes that were signed, send these as they are
+    pub body: Vec<u8>,
+    pub date: String,
+    pub authorization: String,
+}
+
+/// Signs robot messages for Nucleo, see https://developers.neatorobotics.com/api/nucleo
+pub struct Signer {
+    clock: Box<dyn Clock>,
+}
+
+impl Signer {
+    pub fn new(clock: impl Clock + 'static) -> Signer {
+        Signer {
+            clock: Box::new(clock),
+        }
+    }
+
+    /// Serializes `message` and signs the lowercased serial, the RFC 1123 date and the body
+    /// with the robot's secret key
+    pub fn sign(
+        &self,
+        serial: &str,
+        secret_key: &Secret<String>,
+        message: &impl Serialize,
+    ) -> Result<SignedMessage> {
+        let body = serde_json::to_vec(message)?;
+        let date = self
+            .clock
+            .now()
+            .format("%a, %d %b %Y %H:%M:%S GMT")
+            .to_string();
+
+        let mut mac = HmacSha256::new_from_slice(secret_key.expose().as_bytes())
+            .expect("HMAC can take key of any size");
+        mac.update(serial.to_lowercase().as_bytes());
+        mac.update(b"\n");
+        mac.update(date.as_bytes());
+        mac.update(b"\n");
+        mac.update(&body);
+        let signature = hex::encode(mac.finalize().into_bytes());
+
+        Ok(SignedMessage {
+            body,
+            date,
+            authorization: format!("NEATOAPP {}", signature),
+        })
+    }
+}
+
+impl Default for Signer {
+    fn default() -> Signer {
+        Signer::new(SystemClock)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::TimeZone;
+
+    use super::*;
+    use crate::neato_types::{HouseCleaningParams, RobotMessage};
+
+    struct FixedClock;
+
+    impl Clock for FixedClock {
+        fn now(&self) -> DateTime<Utc> {
+            Utc.timestamp_opt(1_700_000_000, 0).unwrap()
+        }
+    }
+
+    const SERIAL: &str = "OPS01234-0123456789AB";
+
+    fn secret_key() -> Secret<String> {
+        Secret::new(String::from("0123456789abcdef0123456789abcdef"))
+    }
+
+    fn get_robot_state() -> RobotMessage {
+        RobotMessage {
+            req_id: String::from("1"),
+            cmd: String::from("getRobotState"),
+            params: None,
+        }
+    }
+
+    #[test]
+    fn date_is_rfc_1123() {
+        let signed = Signer::new(FixedClock)
+            .sign(SERIAL, &secret_key(), &get_robot_state())
+            .unwrap();
+
+        assert_eq!(signed.date, "Tue, 14 Nov 2023 22:13:20 GMT");
+    }
+
+    #[test]
+    fn signs_known_message() {
+        let signed = Signer::new(FixedClock)
+            .sign(SERIAL, &secret_key(), &get_robot_state())
+            .unwrap();
+
+        assert_eq!(
+            signed.body,
+            br#"{"reqId":"1","cmd":"getRobotState","params":null}"#
+        );
+        assert_eq!(
+            signed.authorization,
+            "NEATOAPP 265798d539ada7dbc89db719a1c98c58b34923525af102432a36b166de67c695"
+        );
+    }
+
+    #[test]
+    fn signs_known_message_with_params() {
+        let message = RobotMessage {
+            req_id: String::from("2"),
+            cmd: String::from("startCleaning"),
+            params: Some(HouseCleaningParams {
+                category: 4,
+                mode: 1,
+                navigation_mode: 2,
+            }),
+        };
+        let signed = Signer::new(FixedClock)
+            .sign(SERIAL, &secret_key(), &message)
+            .unwrap();
+
+        assert_eq!(
+            signed.body,
+            br#"{"reqId":"2","cmd":"startCleaning","params":{"category":4,"mode":1,"navigationMode":2}}"#
+        );
+        assert_eq!(
+            signed.authorization,
+            "NEATOAPP f2f032f47faeb25e6071d4ea4f5a14dcae6353fc019231990dfa2204f338e197"
+        );
+    }
+
+    #[test]
+    fn serial_case_does_not_matter() {
+        let signer = Signer::new(FixedClock);
+        let upper = signer
+            .sign(SERIAL, &secret_key(), &get_robot_state())
+            .unwrap();
+        let lower = signer
+            .sign(&SERIAL.to_lowercase(), &secret_key(), &get_robot_state())
+            .unwrap();
+
+        assert_eq!(upper.authorization, lower.authorization);
+    }
+}
@@ -0,0 +1,349 @@
+use std::{collections::HashMap, sync::Mutex};
+
+use async_trait::async_trait;
+use color_eyre::Result;
+use eyre::eyre;
+use log::info;
+use rand::{seq::SliceRandom, Rng};
+use tokio::time::Instant;
+
+use super::RobotBackend;
+use crate::{
+    neato::{Account, RobotCmd},
+    neato_types::{NeatoState, Robot, RobotAction, RobotState, RobotStateDetails},
+    settings::SimulatorSettings,
+};
+
+/// Alerts a real Botvac raises now and then
+const ALERTS: &[&str] = &["dustbin_full", "maint_brush_change", "maint_filter_change"];
+
+/// Robots return to the base by themselves below this charge
+const LOW_CHARGE: f64 = 10.0;
+
+struct SimulatedRobot {
+    state: RobotState,
+    action: RobotAction,
+    charge: f64,
+    is_docked: bool,
+    alert: Option<String>,
+    /// When docking started, if the robot is on its way to the base
+    docking_since: Option<Instant>,
+    last_update: Instant,
+}
+
+impl SimulatedRobot {
+    fn new() -> SimulatedRobot {
+        SimulatedRobot {
+            state: RobotState::Idle,
+            action: RobotAction::Invalid,
+            charge: 100.0,
+            is_docked: true,
+            alert: None,
+            docking_since: None,
+            last_update: Instant::now(),
+        }
+    }
+
+    fn start_docking(&mut self) {
+        self.state = RobotState::Busy;
+        self.action = RobotAction::Docking;
+        self.docking_since = Some(Instant::now());
+    }
+
+    /// Moves the simulation forward to now
+    fn advance(&mut self, settings: &SimulatorSettings) {
+        let now = Instant::now();
+        let minutes = (now - self.last_update).as_secs_f64() * settings.time_scale / 60.0;
+        self.last_update = now;
+
+        match (self.state, self.action) {
+            (RobotState::Busy, RobotAction::HouseCleaning) => {
+                self.charge = (self.charge - settings.battery_drain * minutes).max(0.0);
+                if self.charge <= LOW_CHARGE {
+                    self.start_docking();
+                }
+            }
+            (RobotState::Busy, RobotAction::Docking) => {
+                let docking_for = self
+                    .docking_since
+                    .map(|since| (now - since).as_secs_f64() * settings.time_scale)
+                    .unwrap_or_default();
+                if docking_for >= settings.docking_time as f64 {
+                    self.state = RobotState::Idle;
+                    self.action = RobotAction::Invalid;
+                    self.is_docked = true;
+                    self.docking_since = None;
+                }
+            }
+            _ => {}
+        }
+
+        if self.is_docked {
+            self.charge = (self.charge + settings.charge_rate * minutes).min(100.0);
+        }
+    }
+
+    fn command(&mut self, cmd: &RobotCmd) -> bool {
+        match (cmd, self.state) {
+            (RobotCmd::StartCleaning | RobotCmd::StartCleaningWith(_), RobotState::Idle) => {
+                self.state = RobotState::Busy;
+                self.action = RobotAction::HouseCleaning;
+                self.is_docked = false;
+                // Starting a run means someone emptied the bin or changed the brush
+                self.alert = None;
+            }
+            (RobotCmd::PauseCleaning, RobotState::Busy) => {
+                self.state = RobotState::Paused;
+            }
+            (RobotCmd::ResumeCleaning, RobotState::Paused) => {
+                self.state = RobotState::Busy;
+            }
+            (RobotCmd::StopCleaning, RobotState::Busy | RobotState::Paused) => {
+                self.state = RobotState::Idle;
+                self.action = RobotAction::Invalid;
+                self.docking_since = None;
+            }
+            (RobotCmd::SendToBase, RobotState::Idle | RobotState::Busy | RobotState::Paused)
+                if !self.is_docked =>
+            {
+                self.start_docking();
+            }
+            _ => return false,
+        }
+        true
+    }
+
+    fn neato_state(&self) -> NeatoState {
+        NeatoState {
+            alert: self.alert.clone(),
+            error: None,
+            details: RobotStateDetails {
+                is_charging: self.is_docked && self.charge < 100.0,
+                is_docked: self.is_docked,
+                is_schedule_enabled: false,
+                dock_has_been_seen: true,
+                charge: self.charge.round() as i8,
+            },
+            state: self.state,
+            action: self.action,
+        }
+    }
+}
+
+/// Simulated Botvacs for demos and developing automations without running a real robot
+pub struct SimulatorBackend {
+    settings: SimulatorSettings,
+    robots: Mutex<HashMap<String, SimulatedRobot>>,
+}
+
+impl SimulatorBackend {
+    pub fn new(settings: &SimulatorSettings) -> SimulatorBackend {
+        SimulatorBackend {
+            settings: settings.clone(),
+            robots: Mutex::new(HashMap::new()),
+        }
+    }
+}
+
+fn serial(index: usize) -> String {
+    format!("sim{:08}-{:012}", index + 1, index + 1)
+}
+
+#[async_trait]
+impl RobotBackend for SimulatorBackend {
+    async fn discover_robots(&self, _account: &Account) -> Result<Vec<Robot>> {
+        let mut simulated = self.robots.lock().unwrap();
+        let robots = self
+            .settings
+            .robots
+            .iter()
+            .enumerate()
+            .map(|(i, name)| {
+                simulated
+                    .entry(serial(i))
+                    .or_insert_with(SimulatedRobot::new);
+                serde_json::from_value(serde_json::json!({
+                    "mac_address": format!("{:012}", i + 1),
+                    "model": "BotVacD7Connected",
+                    "name": name,
+                    "nucleo_url": "simulator",
+                    "secret_key": "",
+                    "serial": serial(i),
+                    "firmware": "simulator",
+                    "state": null,
+                }))
+            })
+            .collect::<Result<Vec<Robot>, _>>()?;
+
+        Ok(robots)
+    }
+
+    async fn send_command(&self, robot: &Robot, cmd: &RobotCmd, req_id: &str) -> Result<String> {
+        let mut robots = self.robots.lock().unwrap();
+        let simulated = robots
+            .get_mut(&robot.serial)
+            .ok_or_else(|| eyre!("No simulated robot with serial {}", robot.serial))?;
+        simulated.advance(&self.settings);
+
+        if *cmd == RobotCmd::GetRobotState {
+            let mut rng = rand::thread_rng();
+            if simulated.alert.is_none() && rng.gen_bool(self.settings.alert_probability) {
+                simulated.alert = ALERTS.choose(&mut rng).map(|a| a.to_string());
+                info!(
+                    "Simulated robot {} raised alert {:?}",
+                    robot.name, simulated.alert
+                );
+            }
+            let mut state = serde_json::to_value(simulated.neato_state())?;
+            state["reqId"] = req_id.into();
+            return Ok(state.to_string());
+        }
+
+        let result = if simulated.command(cmd) {
+            info!(
+                "Simulated robot {} is now {}/{}",
+                robot.name, simulated.state, simulated.action
+            );
+            "ok"
+        } else {
+            "invalid_state"
+        };
+
+        Ok(serde_json::json!({ "reqId": req_id, "result": result }).to_string())
+    }
+
+    async fn fetch_maps(&self, _account: &Account, _robot: &Robot) -> Result<serde_json::Value> {
+        Ok(serde_json::json!({ "maps": [] }))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::*;
+    use crate::{backend::fake, settings::Backend};
+
+    /// A simulator with a single robot named Vacuum
+    async fn simulator(settings: SimulatorSettings) -> (SimulatorBackend, Robot) {
+        let mut neato_settings = fake::settings().neato;
+        neato_settings.backend = Backend::Simulator;
+        let account = Account::new(&neato_settings.accounts()[0], &neato_settings);
+        let backend = SimulatorBackend::new(&SimulatorSettings {
+            robots: vec![String::from("Vacuum")],
+            ..settings
+        });
+        let robot = backend.discover_robots(&account).await.unwrap().remove(0);
+        (backend, robot)
+    }
+
+    async fn state(backend: &SimulatorBackend, robot: &Robot) -> NeatoState {
+        let response = backend
+            .send_command(robot, &RobotCmd::GetRobotState, "1")
+            .await
+            .unwrap();
+        serde_json::from_str(&response).unwrap()
+    }
+
+    async fn command(backend: &SimulatorBackend, robot: &Robot, cmd: RobotCmd) -> String {
+        let response = backend.send_command(robot, &cmd, "1").await.unwrap();
+        serde_json::from_str::<serde_json::Value>(&response).unwrap()["result"]
+            .as_str()
+            .unwrap()
+            .to_string()
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn start_cleaning_leaves_the_base() {
+        let (backend, robot) = simulator(fake::settings().simulator).await;
+
+        assert_eq!(
+            command(&backend, &robot, RobotCmd::StartCleaning).await,
+            "ok"
+        );
+
+        let state = state(&backend, &robot).await;
+        assert_eq!(state.state, RobotState::Busy);
+        assert!(matches!(state.action, RobotAction::HouseCleaning));
+        assert!(!state.details.is_docked);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn battery_drains_while_cleaning() {
+        let (backend, robot) = simulator(fake::settings().simulator).await;
+        command(&backend, &robot, RobotCmd::StartCleaning).await;
+
+        tokio::time::advance(Duration::from_secs(30 * 60)).await;
+
+        let state = state(&backend, &robot).await;
+        assert_eq!(state.details.charge, 70);
+        assert_eq!(state.state, RobotState::Busy);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn low_battery_sends_the_robot_home() {
+        let (backend, robot) = simulator(fake::settings().simulator).await;
+        command(&backend, &robot, RobotCmd::StartCleaning).await;
+
+        tokio::time::advance(Duration::from_secs(95 * 60)).await;
+
+        let state = state(&backend, &robot).await;
+        assert!(matches!(state.action, RobotAction::Docking));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn send_to_base_docks_and_charges() {
+        let (backend, robot) = simulator(fake::settings().simulator).await;
+        command(&backend, &robot, RobotCmd::StartCleaning).await;
+        tokio::time::advance(Duration::from_secs(10 * 60)).await;
+
+        assert_eq!(command(&backend, &robot, RobotCmd::SendToBase).await, "ok");
+        let docking = state(&backend, &robot).await;
+        assert_eq!(docking.state, RobotState::Busy);
+        assert!(matches!(docking.action, RobotAction::Docking));
+
+        tokio::time::advance(Duration::from_secs(30)).await;
+        let docked = state(&backend, &robot).await;
+        assert_eq!(docked.state, RobotState::Idle);
+        assert!(docked.details.is_docked);
+        assert!(docked.details.is_charging);
+
+        tokio::time::advance(Duration::from_secs(60 * 60)).await;
+        let charged = state(&backend, &robot).await;
+        assert_eq!(charged.details.charge, 100);
+        assert!(!charged.details.is_charging);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn commands_that_do_not_fit_the_state_are_refused() {
+        let (backend, robot) = simulator(fake::settings().simulator).await;
+
+        assert_eq!(
+            command(&backend, &robot, RobotCmd::SendToBase).await,
+            "invalid_state"
+        );
+        assert_eq!(
+            command(&backend, &robot, RobotCmd::PauseCleaning).await,
+            "invalid_state"
+        );
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn alerts_are_raised_and_cleared_by_the_next_run() {
+        let (backend, robot) = simulator(SimulatorSettings {
+            alert_probability: 1.0,
+            ..fake::settings().simulator
+        })
+        .await;
+
+        let alert = state(&backend, &robot).await.alert.unwrap();
+        assert!(ALERTS.contains(&alert.as_str()));
+        // The alert stays until it is dealt with
+        assert_eq!(state(&backend, &robot).await.alert, Some(alert));
+
+        command(&backend, &robot, RobotCmd::StartCleaning).await;
+        let mut backend = backend;
+        backend.settings.alert_probability = 0.0;
+        assert_eq!(state(&backend, &robot).await.alert, None);
+    }
+}
@@ -0,0 +1,291 @@
+use std::sync::Arc;
+
+use clap::{Parser, Subcommand, ValueEnum};
+use color_eyre::Result;
+use eyre::eyre;
+use serde::Serialize;
+
+use crate::{
+    backend::RobotBackend,
+    neato::{create_accounts, discover_robots, get_robots, send_request, Account, RobotCmd},
+    neato_types::{NeatoState, PublicRobot, Robot},
+    settings::Settings,
+};
+
+#[derive(Parser, Debug)]
+#[command(version, about = "Synchronize Neato Botvac robots with an MQTT broker")]
+pub struct Cli {
+    /// Settings file to use, defaults to the optional `Settings.toml`
+    #[arg(short, long, global = true)]
+    pub config: Option<String>,
+
+    /// Output format for one-shot commands
+    #[arg(short, long, global = true, value_enum, default_value_t = OutputFormat::Table)]
+    pub output: OutputFormat,
+
+    #[command(subcommand)]
+    pub command: Option<Command>,
+}
+
+#[derive(Subcommand, Debug)]
+pub enum Command {
+    /// Run the MQTT bridge (default)
+    Run,
+    /// List robots on the Neato account
+    ListRobots,
+    /// Fetch and print the current state of a robot
+    State { robot: String },
+    /// Send an action, e.g. `start`, `dock` or `sendToBase`, to a robot
+    Send { robot: String, action: String },
+    /// Read and check the settings file, then exit
+    CheckConfig,
+}
+
+#[derive(Clone, Copy, ValueEnum, Debug)]
+pub enum OutputFormat {
+    Json,
+    Table,
+}
+
+fn print_table(headers: &[&str], rows: Vec<Vec<String>>) {
+    let mut widths: Vec<usize> = headers.iter().map(|h| h.len()).collect();
+    for row in &rows {
+        for (width, cell) in widths.iter_mut().zip(row) {
+            *width = (*width).max(cell.len());
+        }
+    }
+
+    let format_row = |cells: Vec<String>| {
+        cells
+            .iter()
+            .zip(&widths)
+            .map(|(cell, width)| format!("{:width$}", cell, width = width))
+            .collect::<Vec<String>>()
+            .join("  ")
+            .trim_end()
+            .to_string()
+    };
+
+    println!(
+        "{}",
+        format_row(headers.iter().map(|h| h.to_string()).collect())
+    );
+    for row in rows {
+        println!("{}", format_row(row));
+    }
+}
+
+fn print_json<T: Serialize>(value: &T) -> Result<()> {
+    println!("{}", serde_json::to_string_pretty(value)?);
+    Ok(())
+}
+
+/// The robot with the given id, along with its account
+async fn find_robot<B: RobotBackend>(
+    backend: &B,
+    settings: &Settings,
+    id: &str,
+) -> Result<(Robot, Option<Arc<Account>>)> {
+    let accounts = create_accounts(&settings.neato);
+    let robot = discover_robots(backend, &accounts, &settings.mqtt)
+        .await?
+        .into_iter()
+        .find(|r| r.id == id)
+        .ok_or_else(|| eyre!("No robot found with id \"{}\"", id))?;
+    let account = accounts.into_iter().find(|a| a.name() == robot.account);
+
+    Ok((robot, account))
+}
+
+pub async fn list_robots<B: RobotBackend>(
+    backend: &B,
+    settings: &Settings,
+    output: OutputFormat,
+) -> Result<()> {
+    let robots: Vec<PublicRobot> = get_robots(backend, settings)
+        .await?
+        .iter()
+        .map(|r| r.to_public())
+        .collect();
+
+    match output {
+        OutputFormat::Json => print_json(&robots)?,
+        OutputFormat::Table => print_table(
+            &["ID", "NAME", "ACCOUNT", "MODEL", "SERIAL", "MAC"],
+            robots
+                .into_iter()
+                .map(|r| vec![r.id, r.name, r.account, r.model, r.serial, r.mac_address])
+                .collect(),
+        ),
+    }
+
+    Ok(())
+}
+
+pub async fn state<B: RobotBackend>(
+    backend: &B,
+    settings: &Settings,
+    output: OutputFormat,
+    id: &str,
+) -> Result<()> {
+    let (mut robot, account) = find_robot(backend, settings, id).await?;
+    let (_, result) = send_request(
+        backend,
+        account.as_deref(),
+        &robot,
+        &RobotCmd::GetRobotState,
+    )
+    .await;
+    let state: NeatoState = serde_json::from_str(&result?)?;
+    robot.state = Some(state.clone());
+
+    match output {
+        OutputFormat::Json => print_json(&robot.to_public())?,
+        OutputFormat::Table => print_table(
+            &[
+                "ID", "STATE", "ACTION", "CHARGE", "DOCKED", "CHARGING", "ALERT", "ERROR",
+            ],
+            vec![vec![
+                robot.id,
+                state.state.to_string(),
+                state.action.to_string(),
+                format!("{}%", state.details.charge),
+                state.details.is_docked.to_string(),
+                state.details.is_charging.to_string(),
+                state.alert.unwrap_or_default(),
+                state.error.unwrap_or_default(),
+            ]],
+        ),
+    }
+
+    Ok(())
+}
+
+/// Action given on the command line, by its short or protocol name like on the set topics
+fn parse_action(action: &str) -> Result<RobotCmd> {
+    RobotCmd::from_name(action).ok_or_else(|| eyre!("Unknown action \"{}\"", action))
+}
+
+pub async fn send<B: RobotBackend>(
+    backend: &B,
+    settings: &Settings,
+    output: OutputFormat,
+    id: &str,
+    action: &str,
+) -> Result<()> {
+    let cmd = parse_action(action)?;
+    let (robot, account) = find_robot(backend, settings, id).await?;
+
+    let response = if settings.neato.dry_run {
+        None
+    } else {
+        let (_, result) = send_request(backend, account.as_deref(), &robot, &cmd).await;
+        let result = result?;
+        Some(serde_json::from_str::<serde_json::Value>(&result).unwrap_or(result.into()))
+    };
+
+    match output {
+        OutputFormat::Json => print_json(&serde_json::json!({
+            "robot": robot.id,
+            "action": cmd,
+            "dry_run": settings.neato.dry_run,
+            "response": response,
+        }))?,
+        OutputFormat::Table => {
+            if response.is_none() {
+                println!("Setting neato.dry_run enabled, not sending command");
+            }
+            print_table(
+                &["ROBOT", "ACTION", "RESPONSE"],
+                vec![vec![
+                    robot.id,
+                    cmd.to_string(),
+                    response.map(|r| r.to_string()).unwrap_or_default(),
+                ]],
+            )
+        }
+    }
+
+    Ok(())
+}
+
+pub fn check_config(settings: &Settings, output: OutputFormat) -> Result<()> {
+    let accounts = settings.neato.accounts();
+    if accounts.is_empty() {
+        return Err(eyre!("No Neato account configured"));
+    }
+
+    match output {
+        OutputFormat::Json => print_json(&serde_json::json!({
+            "ok": true,
+            "mqtt": {
+                "host": settings.mqtt.host,
+                "port": settings.mqtt.port,
+                "topic": settings.mqtt.topic,
+                "set_topic": settings.mqtt.set_topic,
+                "broadcast_topic": settings.mqtt.get_broadcast_topic(),
+            },
+            "neato": {
+                "accounts": accounts
+                    .iter()
+                    .map(|a| serde_json::json!({
+                        "name": a.name(),
+                        "email": a.email,
+                        "topic_prefix": a.topic_prefix,
+                    }))
+                    .collect::<Vec<_>>(),
+                "poll_interval": settings.neato.poll_interval,
+                "cache_timeout": settings.neato.cache_timeout,
+                "dry_run": settings.neato.dry_run,
+            },
+        }))?,
+        OutputFormat::Table => {
+            println!("Configuration OK");
+            let mut rows = vec![
+                vec!["mqtt.host".into(), settings.mqtt.host.clone()],
+                vec!["mqtt.port".into(), settings.mqtt.port.to_string()],
+                vec!["mqtt.topic".into(), settings.mqtt.topic.clone()],
+                vec!["mqtt.set_topic".into(), settings.mqtt.set_topic.clone()],
+                vec![
+                    "mqtt.broadcast_topic".into(),
+                    settings.mqtt.get_broadcast_topic(),
+                ],
+                vec![
+                    "neato.poll_interval".into(),
+                    settings.neato.poll_interval.to_string(),
+                ],
+                vec![
+                    "neato.cache_timeout".into(),
+                    settings.neato.cache_timeout.to_string(),
+                ],
+                vec!["neato.dry_run".into(), settings.neato.dry_run.to_string()],
+            ];
+            for account in accounts.iter() {
+                rows.push(vec![
+                    format!("account {}", account.name()),
+                    format!("{} (prefix \"{}\")", account.email, account.topic_prefix),
+                ]);
+            }
+            print_table(&["SETTING", "VALUE"], rows)
+        }
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn actions_are_parsed_like_on_the_set_topics() {
+        assert_eq!(parse_action("dock").unwrap(), RobotCmd::SendToBase);
+        assert_eq!(parse_action("Start").unwrap(), RobotCmd::StartCleaning);
+        assert_eq!(parse_action("sendToBase").unwrap(), RobotCmd::SendToBase);
+        assert_eq!(
+            parse_action("pause_cleaning").unwrap(),
+            RobotCmd::PauseCleaning
+        );
+        assert!(parse_action("vacuum").is_err());
+    }
+}
@@ -0,0 +1,351 @@
+use bytes::Bytes;
+use eyre::Result;
+use rumqttc::{v5, QoS};
+use serde::Deserialize;
+use serde_repr::Deserialize_repr;
+use std::time::Duration;
+
+use crate::settings::MqttSettings;
+
+/// MQTT protocol version to connect with
+#[derive(Clone, Copy, Deserialize, Debug, PartialEq)]
+pub enum MqttVersion {
+    #[serde(rename = "3.1.1")]
+    V311,
+    #[serde(rename = "5")]
+    V5,
+}
+
+/// QoS level in the settings, `0`, `1` or `2`, named like `rumqttc::QoS`
+#[allow(clippy::enum_variant_names)]
+#[derive(Clone, Copy, Deserialize_repr, Debug, PartialEq)]
+#[repr(u8)]
+pub enum Qos {
+    AtMostOnce = 0,
+    AtLeastOnce = 1,
+    ExactlyOnce = 2,
+}
+
+impl From<Qos> for QoS {
+    fn from(qos: Qos) -> QoS {
+        match qos {
+            Qos::AtMostOnce => QoS::AtMostOnce,
+            Qos::AtLeastOnce => QoS::AtLeastOnce,
+            Qos::ExactlyOnce => QoS::ExactlyOnce,
+        }
+    }
+}
+
+/// MQTT 5 properties of a publish, ignored when connected with MQTT 3.1.1
+#[derive(Clone, Debug, Default)]
+pub struct Properties {
+    /// Seconds after which the broker drops the message if it has not been delivered
+    pub message_expiry: Option<u32>,
+    pub correlation_data: Option<Bytes>,
+    pub user_properties: Vec<(String, String)>,
+}
+
+/// Where the sender of a command wants the result, from the MQTT 5 `ResponseTopic` and
+/// `CorrelationData` of the command
+#[derive(Clone, Debug, PartialEq)]
+pub struct ResponseTo {
+    pub topic: String,
+    pub correlation_data: Option<Bytes>,
+}
+
+/// A message received on one of the subscriptions
+#[derive(Debug)]
+pub struct Message {
+    pub topic: String,
+    pub payload: Bytes,
+    pub response_to: Option<ResponseTo>,
+}
+
+pub enum Notification {
+    ConnAck,
+    Message(Message),
+    /// The disconnect asked for with `Client::disconnect` was sent
+    Disconnected,
+    Other,
+}
+
+/// Message the broker publishes with QoS 1 when a connection is lost without a disconnect
+#[derive(Clone, Debug, PartialEq)]
+pub struct Will {
+    pub topic: String,
+    pub payload: String,
+    pub retain: bool,
+}
+
+fn v5_qos(qos: QoS) -> v5::mqttbytes::QoS {
+    match qos {
+        QoS::AtMostOnce => v5::mqttbytes::QoS::AtMostOnce,
+        QoS::AtLeastOnce => v5::mqttbytes::QoS::AtLeastOnce,
+        QoS::ExactlyOnce => v5::mqttbytes::QoS::ExactlyOnce,
+    }
+}
+
+// The client id is `mqtt.id` as is, so that the broker can resume a persistent session
+fn options(mqtt_settings: &MqttSettings) -> rumqttc::MqttOptions {
+    let mut options = rumqttc::MqttOptions::new(
+        mqtt_settings.id.clone(),
+        mqtt_settings.host.clone(),
+        mqtt_settings.port,
+    );
+    options
+        .set_keep_alive(Duration::from_secs(mqtt_settings.keep_alive))
+        .set_clean_session(mqtt_settings.clean_session)
+        .set_inflight(mqtt_settings.inflight);
+    options
+}
+
+fn v5_options(mqtt_settings: &MqttSettings) -> v5::MqttOptions {
+    let mut options = v5::MqttOptions::new(
+        mqtt_settings.id.clone(),
+        mqtt_settings.host.clone(),
+        mqtt_settings.port,
+    );
+    // rumqttc panics on MQTT 5 keep alives below 5 seconds, validation reports them
+    options
+        .set_keep_alive(Duration::from_secs(mqtt_settings.keep_alive.max(5)))
+        .set_clean_start(mqtt_settings.clean_session)
+        .set_outgoing_inflight_upper_limit(mqtt_settings.inflight);
+    if !mqtt_settings.clean_session {
+        // MQTT 5 ends the session on disconnect unless it has an expiry interval
+        let mut properties = v5::mqttbytes::v5::ConnectProperties::new();
+        properties.session_expiry_interval = Some(mqtt_settings.session_expiry);
+        options.set_connect_properties(properties);
+    }
+    options
+}
+
+/// Client for either MQTT version, publishes are queued to the `Connection`
+#[derive(Clone)]
+pub enum Client {
+    V311(rumqttc::AsyncClient),
+    V5(v5::AsyncClient),
+}
+
+impl Client {
+    pub fn new(mqtt_settings: &MqttSettings) -> (Client, Connection) {
+        let cap = mqtt_settings.channel_capacity;
+        match mqtt_settings.version {
+            MqttVersion::V311 => {
+                let (client, eventloop) = rumqttc::AsyncClient::new(options(mqtt_settings), cap);
+                (Client::V311(client), Connection::V311(eventloop))
+            }
+            MqttVersion::V5 => {
+                let (client, eventloop) = v5::AsyncClient::new(v5_options(mqtt_settings), cap);
+                (Client::V5(client), Connection::V5(eventloop))
+            }
+        }
+    }
+
+    /// Client with a clean session under `client_id` that leaves `will` with the broker
+    pub fn with_will(
+        mqtt_settings: &MqttSettings,
+        client_id: String,
+        will: &Will,
+    ) -> (Client, Connection) {
+        let settings = MqttSettings {
+            id: client_id,
+            clean_session: true,
+            ..mqtt_settings.clone()
+        };
+        let cap = settings.channel_capacity;
+        match settings.version {
+            MqttVersion::V311 => {
+                let mut options = options(&settings);
+                options.set_last_will(rumqttc::LastWill::new(
+                    &will.topic,
+                    will.payload.as_str(),
+                    QoS::AtLeastOnce,
+                    will.retain,
+                ));
+                let (client, eventloop) = rumqttc::AsyncClient::new(options, cap);
+                (Client::V311(client), Connection::V311(eventloop))
+            }
+            MqttVersion::V5 => {
+                let mut options = v5_options(&settings);
+                options.set_last_will(v5::mqttbytes::v5::LastWill::new(
+                    &will.topic,
+                    will.payload.as_str(),
+                    v5::mqttbytes::QoS::AtLeastOnce,
+                    will.retain,
+                    None,
+                ));
+                let (client, eventloop) = v5::AsyncClient::new(options, cap);
+                (Client::V5(client), Connection::V5(eventloop))
+            }
+        }
+    }
+
+    pub async fn publish(
+        &self,
+        topic: impl Into<String>,
+        qos: QoS,
+        retain: bool,
+        payload: impl Into<Vec<u8>>,
+    ) -> Result<()> {
+        self.publish_with_properties(topic, qos, retain, payload, Properties::default())
+            .await
+    }
+
+    pub async fn publish_with_properties(
+        &self,
+        topic: impl Into<String>,
+        qos: QoS,
+        retain: bool,
+        payload: impl Into<Vec<u8>>,
+        properties: Properties,
+    ) -> Result<()> {
+        match self {
+            Client::V311(client) => client.publish(topic, qos, retain, payload).await?,
+            Client::V5(client) => {
+                let properties = v5::mqttbytes::v5::PublishProperties {
+                    message_expiry_interval: properties.message_expiry,
+                    correlation_data: properties.correlation_data,
+                    user_properties: properties.user_properties,
+                    ..Default::default()
+                };
+                client
+                    .publish_with_properties(
+                        topic,
+                        v5_qos(qos),
+                        retain,
+                        Bytes::from(payload.into()),
+                        properties,
+                    )
+                    .await?
+            }
+        }
+        Ok(())
+    }
+
+    pub async fn subscribe(&self, topic: impl Into<String>, qos: QoS) -> Result<()> {
+        match self {
+            Client::V311(client) => client.subscribe(topic, qos).await?,
+            Client::V5(client) => client.subscribe(topic, v5_qos(qos)).await?,
+        }
+        Ok(())
+    }
+
+    pub async fn unsubscribe(&self, topic: impl Into<String>) -> Result<()> {
+        match self {
+            Client::V311(client) => client.unsubscribe(topic).await?,
+            Client::V5(client) => client.unsubscribe(topic).await?,
+        }
+        Ok(())
+    }
+
+    /// Queues a disconnect without waiting for room in the channel
+    pub fn try_disconnect(&self) -> Result<()> {
+        match self {
+            Client::V311(client) => client.try_disconnect()?,
+            Client::V5(client) => client.try_disconnect()?,
+        }
+        Ok(())
+    }
+}
+
+/// Event loop of a `Client`, has to be polled to make progress
+pub enum Connection {
+    V311(rumqttc::EventLoop),
+    V5(v5::EventLoop),
+}
+
+impl Connection {
+    pub async fn poll(&mut self) -> Result<Notification> {
+        let notification = match self {
+            Connection::V311(eventloop) => {
+                let event = eventloop.poll().await?;
+                log::debug!("Notification: {:?}", event);
+                match event {
+                    rumqttc::Event::Incoming(rumqttc::Packet::ConnAck(_)) => Notification::ConnAck,
+                    rumqttc::Event::Outgoing(rumqttc::Outgoing::Disconnect) => {
+                        Notification::Disconnected
+                    }
+                    rumqttc::Event::Incoming(rumqttc::Packet::Publish(msg)) => {
+                        Notification::Message(Message {
+                            topic: msg.topic,
+                            payload: msg.payload,
+                            response_to: None,
+                        })
+                    }
+                    _ => Notification::Other,
+                }
+            }
+            Connection::V5(eventloop) => {
+                let event = eventloop.poll().await?;
+                log::debug!("Notification: {:?}", event);
+                match event {
+                    v5::Event::Incoming(v5::Incoming::ConnAck(_)) => Notification::ConnAck,
+                    v5::Event::Outgoing(rumqttc::Outgoing::Disconnect) => {
+                        Notification::Disconnected
+                    }
+                    v5::Event::Incoming(v5::Incoming::Publish(msg)) => {
+                        let response_to = msg.properties.and_then(|p| {
+                            p.response_topic.map(|topic| ResponseTo {
+                                topic,
+                                correlation_data: p.correlation_data,
+                            })
+                        });
+                        Notification::Message(Message {
+                            topic: String::from_utf8_lossy(&msg.topic).to_string(),
+                            payload: msg.payload,
+                            response_to,
+                        })
+                    }
+                    _ => Notification::Other,
+                }
+            }
+        };
+        Ok(notification)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn will() -> Will {
+        Will {
+            topic: String::from("home/devices/neato/Vacuum/availability"),
+            payload: String::from("offline"),
+            retain: true,
+        }
+    }
+
+    #[test]
+    fn wills_are_retained_with_qos_1() {
+        let mut settings = crate::backend::fake::settings().mqtt;
+
+        let (_, connection) =
+            Client::with_will(&settings, String::from("neato-mqtt-vacuum"), &will());
+        let Connection::V311(eventloop) = connection else {
+            panic!("expected an MQTT 3.1.1 connection");
+        };
+        let options = &eventloop.mqtt_options;
+        assert_eq!(options.client_id(), "neato-mqtt-vacuum");
+        assert!(options.clean_session());
+        let last_will = options.last_will().unwrap();
+        assert_eq!(last_will.topic, "home/devices/neato/Vacuum/availability");
+        assert_eq!(&last_will.message[..], b"offline");
+        assert_eq!(last_will.qos, QoS::AtLeastOnce);
+        assert!(last_will.retain);
+
+        settings.version = MqttVersion::V5;
+        let (_, connection) =
+            Client::with_will(&settings, String::from("neato-mqtt-vacuum"), &will());
+        let Connection::V5(eventloop) = connection else {
+            panic!("expected an MQTT 5 connection");
+        };
+        let last_will = eventloop.options.last_will().unwrap();
+        assert_eq!(
+            &last_will.topic[..],
+            b"home/devices/neato/Vacuum/availability"
+        );
+        assert_eq!(last_will.qos, v5::mqttbytes::QoS::AtLeastOnce);
+        assert!(last_will.retain);
+    }
+}
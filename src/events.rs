@@ -0,0 +1,181 @@
+use chrono::{DateTime, Utc};
+use serde::Serialize;
+
+use crate::neato_types::{NeatoState, RobotAction, RobotState};
+
+#[derive(Clone, Copy, Serialize, Debug, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum EventKind {
+    CleaningStarted,
+    CleaningPaused,
+    CleaningResumed,
+    CleaningFinished,
+    Docked,
+    ChargingComplete,
+    ErrorRaised,
+    ErrorCleared,
+    AlertRaised,
+    AlertCleared,
+    /// Charge dropped below `events.battery_threshold`
+    BatteryLow,
+    /// The robot answers again after being offline
+    BackOnline,
+}
+
+/// A transition observed between two polls, published to `{topic}/events`
+#[derive(Clone, Serialize, Debug)]
+pub struct Event {
+    pub event: EventKind,
+    pub time: DateTime<Utc>,
+    pub previous: Option<NeatoState>,
+    pub state: Option<NeatoState>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub offline_since: Option<DateTime<Utc>>,
+}
+
+impl Event {
+    pub fn new(event: EventKind, previous: Option<&NeatoState>, state: &NeatoState) -> Event {
+        Event {
+            event,
+            time: Utc::now(),
+            previous: previous.cloned(),
+            state: Some(state.clone()),
+            offline_since: None,
+        }
+    }
+}
+
+fn is_cleaning(state: &NeatoState) -> bool {
+    state.state == RobotState::Busy
+        && matches!(
+            state.action,
+            RobotAction::HouseCleaning
+                | RobotAction::SpotCleaning
+                | RobotAction::ManualCleaning
+                | RobotAction::MapCleaning
+        )
+}
+
+/// Events for the transition from `previous` to `new`, in a stable order
+pub fn detect(previous: &NeatoState, new: &NeatoState, battery_threshold: i8) -> Vec<EventKind> {
+    let mut events = Vec::new();
+
+    let was_paused = previous.state == RobotState::Paused;
+    let is_paused = new.state == RobotState::Paused;
+    if !is_cleaning(previous) && is_cleaning(new) {
+        events.push(if was_paused {
+            EventKind::CleaningResumed
+        } else {
+            EventKind::CleaningStarted
+        });
+    }
+    if !was_paused && is_paused {
+        events.push(EventKind::CleaningPaused);
+    }
+    if (is_cleaning(previous) || was_paused) && !is_cleaning(new) && !is_paused {
+        events.push(EventKind::CleaningFinished);
+    }
+
+    let (before, after) = (&previous.details, &new.details);
+    if !before.is_docked && after.is_docked {
+        events.push(EventKind::Docked);
+    }
+    if before.is_charging && !after.is_charging && after.is_docked {
+        events.push(EventKind::ChargingComplete);
+    }
+    if before.charge >= battery_threshold && after.charge < battery_threshold {
+        events.push(EventKind::BatteryLow);
+    }
+
+    if new.error.is_some() && new.error != previous.error {
+        events.push(EventKind::ErrorRaised);
+    } else if previous.error.is_some() && new.error.is_none() {
+        events.push(EventKind::ErrorCleared);
+    }
+    if new.alert.is_some() && new.alert != previous.alert {
+        events.push(EventKind::AlertRaised);
+    } else if previous.alert.is_some() && new.alert.is_none() {
+        events.push(EventKind::AlertCleared);
+    }
+
+    events
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::backend::fake;
+
+    fn state(state: RobotState, action: RobotAction, is_docked: bool, charge: i8) -> NeatoState {
+        let mut state = fake::state(state, is_docked, charge);
+        state.action = action;
+        state.details.is_charging = is_docked && charge < 100;
+        state
+    }
+
+    #[test]
+    fn leaving_the_dock_to_clean() {
+        let docked = state(RobotState::Idle, RobotAction::Invalid, true, 100);
+        let cleaning = state(RobotState::Busy, RobotAction::HouseCleaning, false, 99);
+
+        assert_eq!(
+            detect(&docked, &cleaning, 20),
+            vec![EventKind::CleaningStarted]
+        );
+    }
+
+    #[test]
+    fn pausing_and_resuming() {
+        let cleaning = state(RobotState::Busy, RobotAction::HouseCleaning, false, 80);
+        let paused = state(RobotState::Paused, RobotAction::HouseCleaning, false, 80);
+
+        assert_eq!(
+            detect(&cleaning, &paused, 20),
+            vec![EventKind::CleaningPaused]
+        );
+        assert_eq!(
+            detect(&paused, &cleaning, 20),
+            vec![EventKind::CleaningResumed]
+        );
+    }
+
+    #[test]
+    fn returning_to_the_dock_on_low_battery() {
+        let cleaning = state(RobotState::Busy, RobotAction::HouseCleaning, false, 21);
+        let docking = state(RobotState::Busy, RobotAction::Docking, false, 19);
+        let docked = state(RobotState::Idle, RobotAction::Invalid, true, 19);
+
+        assert_eq!(
+            detect(&cleaning, &docking, 20),
+            vec![EventKind::CleaningFinished, EventKind::BatteryLow]
+        );
+        assert_eq!(detect(&docking, &docked, 20), vec![EventKind::Docked]);
+    }
+
+    #[test]
+    fn charging_complete() {
+        let charging = state(RobotState::Idle, RobotAction::Invalid, true, 99);
+        let charged = state(RobotState::Idle, RobotAction::Invalid, true, 100);
+
+        assert_eq!(
+            detect(&charging, &charged, 20),
+            vec![EventKind::ChargingComplete]
+        );
+    }
+
+    #[test]
+    fn alerts_and_errors() {
+        let idle = state(RobotState::Idle, RobotAction::Invalid, true, 100);
+        let mut alert = idle.clone();
+        alert.alert = Some(String::from("dustbin_full"));
+        let mut error = idle.clone();
+        error.state = RobotState::Error;
+        error.error = Some(String::from("ui_error_brush_stuck"));
+
+        assert_eq!(detect(&idle, &alert, 20), vec![EventKind::AlertRaised]);
+        assert_eq!(detect(&alert, &idle, 20), vec![EventKind::AlertCleared]);
+        assert_eq!(detect(&idle, &error, 20), vec![EventKind::ErrorRaised]);
+        assert_eq!(detect(&error, &idle, 20), vec![EventKind::ErrorCleared]);
+        assert!(detect(&idle, &idle, 20).is_empty());
+    }
+}
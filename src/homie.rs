@@ -0,0 +1,341 @@
+use crate::{
+    neato::{RobotCmd, PLAIN_COMMANDS},
+    neato_types::{PublicRobot, RobotState},
+    topics::slugify,
+};
+
+/// Base topic of all Homie devices
+const ROOT: &str = "homie";
+
+/// Where controllers set the `cleaning/command` property of any device
+pub const SET_SUBSCRIPTION: &str = "homie/+/cleaning/command/set";
+
+/// Values of the settable `cleaning/command` property
+const COMMANDS: &[(&str, RobotCmd)] = PLAIN_COMMANDS;
+
+/// Homie device ids may only contain lowercase letters, digits and hyphens
+pub fn device_id(robot_id: &str) -> String {
+    let ascii: String = robot_id
+        .chars()
+        .map(|c| if c.is_ascii() { c } else { '-' })
+        .collect();
+    match slugify(&ascii) {
+        id if id.is_empty() => String::from("robot"),
+        id => id,
+    }
+}
+
+struct Property {
+    id: &'static str,
+    name: &'static str,
+    datatype: &'static str,
+    format: Option<String>,
+    unit: Option<&'static str>,
+    settable: bool,
+}
+
+impl Property {
+    fn new(id: &'static str, name: &'static str, datatype: &'static str) -> Property {
+        Property {
+            id,
+            name,
+            datatype,
+            format: None,
+            unit: None,
+            settable: false,
+        }
+    }
+}
+
+struct Node {
+    id: &'static str,
+    name: &'static str,
+    kind: &'static str,
+    properties: Vec<Property>,
+}
+
+fn nodes() -> Vec<Node> {
+    let states = [
+        RobotState::Invalid,
+        RobotState::Idle,
+        RobotState::Busy,
+        RobotState::Paused,
+        RobotState::Error,
+    ];
+    vec![
+        Node {
+            id: "status",
+            name: "Status",
+            kind: "Robot state",
+            properties: vec![
+                Property {
+                    format: Some(states.map(|s| s.to_string()).join(",")),
+                    ..Property::new("state", "State", "enum")
+                },
+                Property::new("action", "Action", "string"),
+                Property::new("alert", "Alert", "string"),
+                Property::new("error", "Error", "string"),
+                Property::new("online", "Online", "boolean"),
+            ],
+        },
+        Node {
+            id: "battery",
+            name: "Battery",
+            kind: "Battery",
+            properties: vec![
+                Property {
+                    format: Some(String::from("0:100")),
+                    unit: Some("%"),
+                    ..Property::new("charge", "Charge", "integer")
+                },
+                Property::new("charging", "Charging", "boolean"),
+                Property::new("docked", "Docked", "boolean"),
+            ],
+        },
+        Node {
+            id: "cleaning",
+            name: "Cleaning",
+            kind: "Commands",
+            properties: vec![Property {
+                format: Some(
+                    COMMANDS
+                        .iter()
+                        .map(|(name, _)| *name)
+                        .collect::<Vec<_>>()
+                        .join(","),
+                ),
+                settable: true,
+                ..Property::new("command", "Command", "enum")
+            }],
+        },
+    ]
+}
+
+fn device_state(robot: &PublicRobot) -> &'static str {
+    match &robot.state {
+        _ if !robot.online => "lost",
+        Some(state) if state.state == RobotState::Error => "alert",
+        _ => "ready",
+    }
+}
+
+/// Device, node and property attributes as (topic, payload), all retained. `$state` is
+/// `init` until the values are published.
+pub fn description(robot: &PublicRobot) -> Vec<(String, String)> {
+    let device = format!("{}/{}", ROOT, device_id(&robot.id));
+    let nodes = nodes();
+    let mut messages = vec![
+        (format!("{}/$homie", device), String::from("4.0.0")),
+        (format!("{}/$name", device), robot.name.clone()),
+        (format!("{}/$state", device), String::from("init")),
+        (
+            format!("{}/$nodes", device),
+            nodes.iter().map(|n| n.id).collect::<Vec<_>>().join(","),
+        ),
+        (format!("{}/$extensions", device), String::new()),
+    ];
+    for node in nodes.iter() {
+        let node_topic = format!("{}/{}", device, node.id);
+        messages.push((format!("{}/$name", node_topic), node.name.to_string()));
+        messages.push((format!("{}/$type", node_topic), node.kind.to_string()));
+        messages.push((
+            format!("{}/$properties", node_topic),
+            node.properties
+                .iter()
+                .map(|p| p.id)
+                .collect::<Vec<_>>()
+                .join(","),
+        ));
+        for property in node.properties.iter() {
+            let topic = format!("{}/{}", node_topic, property.id);
+            messages.push((format!("{}/$name", topic), property.name.to_string()));
+            messages.push((
+                format!("{}/$datatype", topic),
+                property.datatype.to_string(),
+            ));
+            if let Some(format) = &property.format {
+                messages.push((format!("{}/$format", topic), format.clone()));
+            }
+            if let Some(unit) = property.unit {
+                messages.push((format!("{}/$unit", topic), unit.to_string()));
+            }
+            if property.settable {
+                messages.push((format!("{}/$settable", topic), String::from("true")));
+                // Commands are events, not state
+                messages.push((format!("{}/$retained", topic), String::from("false")));
+            }
+        }
+    }
+    messages
+}
+
+/// `$state` the broker publishes as the will of the device, as (topic, payload)
+pub fn will(robot_id: &str) -> (String, String) {
+    (
+        format!("{}/{}/$state", ROOT, device_id(robot_id)),
+        String::from("lost"),
+    )
+}
+
+/// Property values and the device `$state` as (topic, payload), all retained
+pub fn values(robot: &PublicRobot) -> Vec<(String, String)> {
+    let device = format!("{}/{}", ROOT, device_id(&robot.id));
+    let mut messages = vec![(
+        format!("{}/status/online", device),
+        robot.online.to_string(),
+    )];
+    if let Some(state) = &robot.state {
+        let details = &state.details;
+        messages.extend([
+            (format!("{}/status/state", device), state.state.to_string()),
+            (
+                format!("{}/status/action", device),
+                state.action.to_string(),
+            ),
+            (
+                format!("{}/status/alert", device),
+                state.alert.clone().unwrap_or_default(),
+            ),
+            (
+                format!("{}/status/error", device),
+                state.error.clone().unwrap_or_default(),
+            ),
+            (
+                format!("{}/battery/charge", device),
+                details.charge.to_string(),
+            ),
+            (
+                format!("{}/battery/charging", device),
+                details.is_charging.to_string(),
+            ),
+            (
+                format!("{}/battery/docked", device),
+                details.is_docked.to_string(),
+            ),
+        ]);
+    }
+    // Last, so that controllers see a complete device once it is ready
+    messages.push((
+        format!("{}/$state", device),
+        device_state(robot).to_string(),
+    ));
+    messages
+}
+
+/// Device id and command of a message on `homie/{device}/cleaning/command/set`
+pub fn parse_set(topic: &str, payload: &str) -> Option<(String, Result<RobotCmd, String>)> {
+    let device = topic
+        .strip_prefix(ROOT)?
+        .strip_prefix('/')?
+        .strip_suffix("/cleaning/command/set")?;
+    if device.is_empty() || device.contains('/') {
+        return None;
+    }
+    let command = COMMANDS
+        .iter()
+        .find(|(name, _)| *name == payload.trim())
+        .map(|(_, cmd)| cmd.clone())
+        .ok_or_else(|| {
+            format!(
+                "Unknown Homie command {:?}, expected one of {}",
+                payload,
+                COMMANDS
+                    .iter()
+                    .map(|(name, _)| *name)
+                    .collect::<Vec<_>>()
+                    .join(", ")
+            )
+        });
+    Some((device.to_string(), command))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::backend::fake;
+
+    fn robot(online: bool) -> PublicRobot {
+        PublicRobot {
+            state: Some(fake::state(RobotState::Idle, true, 87)),
+            online,
+            ..fake::robot("Living Room").to_public()
+        }
+    }
+
+    fn get<'a>(messages: &'a [(String, String)], topic: &str) -> Option<&'a str> {
+        messages
+            .iter()
+            .find(|(t, _)| t == topic)
+            .map(|(_, payload)| payload.as_str())
+    }
+
+    #[test]
+    fn device_ids_are_lowercase_ascii() {
+        assert_eq!(device_id("Living Room"), "living-room");
+        assert_eq!(device_id("Küche 2"), "k-che-2");
+        assert_eq!(device_id("!!"), "robot");
+    }
+
+    #[test]
+    fn description_lists_nodes_and_properties() {
+        let messages = description(&robot(true));
+
+        assert_eq!(get(&messages, "homie/living-room/$homie"), Some("4.0.0"));
+        assert_eq!(
+            get(&messages, "homie/living-room/$nodes"),
+            Some("status,battery,cleaning")
+        );
+        assert_eq!(
+            get(&messages, "homie/living-room/battery/$properties"),
+            Some("charge,charging,docked")
+        );
+        assert_eq!(
+            get(&messages, "homie/living-room/status/state/$format"),
+            Some("Invalid,Idle,Busy,Paused,Error")
+        );
+        assert_eq!(
+            get(&messages, "homie/living-room/cleaning/command/$settable"),
+            Some("true")
+        );
+        assert_eq!(
+            get(&messages, "homie/living-room/battery/charge/$settable"),
+            None
+        );
+    }
+
+    #[test]
+    fn values_follow_the_robot_state() {
+        let messages = values(&robot(true));
+
+        assert_eq!(
+            get(&messages, "homie/living-room/battery/charge"),
+            Some("87")
+        );
+        assert_eq!(
+            get(&messages, "homie/living-room/status/state"),
+            Some("Idle")
+        );
+        assert_eq!(get(&messages, "homie/living-room/status/alert"), Some(""));
+        assert_eq!(get(&messages, "homie/living-room/$state"), Some("ready"));
+        assert_eq!(
+            get(&values(&robot(false)), "homie/living-room/$state"),
+            Some("lost")
+        );
+    }
+
+    #[test]
+    fn set_messages_are_parsed() {
+        assert_eq!(
+            parse_set("homie/living-room/cleaning/command/set", "dock"),
+            Some((String::from("living-room"), Ok(RobotCmd::SendToBase)))
+        );
+        assert!(matches!(
+            parse_set("homie/living-room/cleaning/command/set", "fly"),
+            Some((_, Err(_)))
+        ));
+        assert_eq!(
+            parse_set("homie/living-room/status/state/set", "Idle"),
+            None
+        );
+    }
+}
@@ -0,0 +1,368 @@
+use axum::{
+    extract::{Path, State},
+    http::StatusCode,
+    response::{IntoResponse, Response},
+    routing::{get, post},
+    Json, Router,
+};
+use eyre::Result;
+use log::{error, info};
+use serde::Serialize;
+use std::net::SocketAddr;
+use tokio::{
+    sync::{oneshot, watch::Receiver},
+    task,
+};
+
+use crate::{
+    backend::RobotBackend,
+    mqtt::{MqttSetMessage, SendAction},
+    neato::{CommandFailures, Neato, RobotCmd},
+    neato_types::PublicRobot,
+    settings::{HttpSettings, Settings},
+};
+
+#[derive(Serialize)]
+struct CommandResponse {
+    action: RobotCmd,
+    robots: Vec<String>,
+    dry_run: bool,
+}
+
+#[derive(Serialize)]
+struct ErrorResponse {
+    error: String,
+}
+
+/// A command to several robots that failed for some of them
+#[derive(Serialize)]
+struct PartialCommandResponse {
+    action: RobotCmd,
+    /// Robots that got the command
+    robots: Vec<String>,
+    /// Robots that didn't
+    failed: Vec<String>,
+    error: String,
+}
+
+fn error_response(status: StatusCode, error: String) -> Response {
+    (status, Json(ErrorResponse { error })).into_response()
+}
+
+async fn list_robots<B: RobotBackend>(State(neato): State<Neato<B>>) -> Json<Vec<PublicRobot>> {
+    Json(neato.robots().iter().map(|r| r.to_public()).collect())
+}
+
+async fn get_robot<B: RobotBackend>(
+    State(neato): State<Neato<B>>,
+    Path(id): Path<String>,
+) -> Response {
+    match neato.robots().iter().find(|r| r.id == id) {
+        Some(robot) => Json(robot.to_public()).into_response(),
+        None => error_response(
+            StatusCode::NOT_FOUND,
+            format!("No robot found with id \"{}\"", id),
+        ),
+    }
+}
+
+async fn get_maps<B: RobotBackend>(
+    State(neato): State<Neato<B>>,
+    Path(id): Path<String>,
+) -> Response {
+    if !neato.robots().iter().any(|r| r.id == id) {
+        return error_response(
+            StatusCode::NOT_FOUND,
+            format!("No robot found with id \"{}\"", id),
+        );
+    }
+
+    match neato.maps(&id).await {
+        Ok(maps) => Json(maps).into_response(),
+        Err(err) => {
+            error!("Error fetching maps: {}", err);
+            error_response(StatusCode::BAD_GATEWAY, err.to_string())
+        }
+    }
+}
+
+async fn get_requests<B: RobotBackend>(
+    State(neato): State<Neato<B>>,
+    Path(id): Path<String>,
+) -> Response {
+    match neato.robots().iter().find(|r| r.id == id) {
+        Some(robot) => Json(neato.request_log(robot)).into_response(),
+        None => error_response(
+            StatusCode::NOT_FOUND,
+            format!("No robot found with id \"{}\"", id),
+        ),
+    }
+}
+
+async fn send_command<B: RobotBackend>(
+    State(neato): State<Neato<B>>,
+    Path(id): Path<String>,
+    Json(payload): Json<MqttSetMessage>,
+) -> Response {
+    // "set" addresses all robots, same as the MQTT broadcast topic
+    if !neato.is_target(&id) {
+        return error_response(
+            StatusCode::NOT_FOUND,
+            format!("No robot or group found with id \"{}\"", id),
+        );
+    }
+
+    let action = payload.action.clone();
+    match neato
+        .handle_action(SendAction {
+            id,
+            action: payload.action,
+        })
+        .await
+    {
+        Ok(robots) => Json(CommandResponse {
+            action,
+            robots,
+            dry_run: neato.dry_run(),
+        })
+        .into_response(),
+        Err(err) => {
+            error!("Error handling command: {}", err);
+            match err.downcast_ref::<CommandFailures>() {
+                Some(failures) => (
+                    StatusCode::BAD_GATEWAY,
+                    Json(PartialCommandResponse {
+                        action,
+                        robots: failures.succeeded.clone(),
+                        failed: failures.failed.iter().map(|(id, _)| id.clone()).collect(),
+                        error: err.to_string(),
+                    }),
+                )
+                    .into_response(),
+                None => error_response(StatusCode::BAD_GATEWAY, err.to_string()),
+            }
+        }
+    }
+}
+
+/// A running listener, stopped when the HTTP settings change
+struct Listener {
+    shutdown: oneshot::Sender<()>,
+    server: task::JoinHandle<()>,
+}
+
+impl Listener {
+    /// Stops accepting requests and waits until the address is free again
+    async fn stop(self) {
+        let _ = self.shutdown.send(());
+        let _ = self.server.await;
+    }
+}
+
+/// Starts the listener if the API is enabled
+fn serve<B: RobotBackend>(
+    http_settings: &HttpSettings,
+    neato: Neato<B>,
+) -> Result<Option<Listener>> {
+    if !http_settings.enabled {
+        return Ok(None);
+    }
+    let addr: SocketAddr = format!("{}:{}", http_settings.host, http_settings.port).parse()?;
+
+    let app = Router::new()
+        .route("/robots", get(list_robots::<B>))
+        .route("/robots/:id", get(get_robot::<B>))
+        .route("/robots/:id/maps", get(get_maps::<B>))
+        .route("/robots/:id/requests", get(get_requests::<B>))
+        .route("/robots/:id/commands", post(send_command::<B>))
+        .with_state(neato);
+
+    let (shutdown, stopped) = oneshot::channel();
+    let server = axum::Server::try_bind(&addr)?
+        .serve(app.into_make_service())
+        .with_graceful_shutdown(async {
+            let _ = stopped.await;
+        });
+    info!("HTTP API listening on http://{}", addr);
+
+    let server = task::spawn(async move {
+        if let Err(err) = server.await {
+            error!("HTTP server error: {}", err);
+        }
+    });
+
+    Ok(Some(Listener { shutdown, server }))
+}
+
+/// Serves the API while `http.enabled` is set, the listener is started again when the HTTP
+/// settings change
+pub async fn init<B: RobotBackend>(settings: Receiver<Settings>, neato: Neato<B>) -> Result<()> {
+    let mut settings = settings;
+    let mut current = settings.borrow_and_update().http.clone();
+    let mut listener = serve(&current, neato.clone())?;
+
+    task::spawn(async move {
+        while settings.changed().await.is_ok() {
+            let new = settings.borrow_and_update().http.clone();
+            if new == current {
+                continue;
+            }
+            info!("HTTP API settings changed, restarting the listener");
+            if let Some(listener) = listener.take() {
+                listener.stop().await;
+            }
+            listener = serve(&new, neato.clone()).unwrap_or_else(|err| {
+                error!("Could not start the HTTP API: {}", err);
+                None
+            });
+            current = new;
+        }
+    });
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use axum::body::HttpBody;
+    use std::time::Duration;
+
+    use super::*;
+    use crate::{
+        backend::{
+            fake::{self, FakeBackend},
+            ErrorKind,
+        },
+        neato::tests::neato,
+        settings::GroupSettings,
+    };
+
+    /// Status and JSON body of the response to a command posted to `/robots/{id}/commands`
+    async fn post(
+        neato: &Neato<FakeBackend>,
+        id: &str,
+        action: &str,
+    ) -> (StatusCode, serde_json::Value) {
+        let payload = serde_json::from_value(serde_json::json!({ "action": action })).unwrap();
+        let response =
+            send_command(State(neato.clone()), Path(id.to_string()), Json(payload)).await;
+
+        let status = response.status();
+        let mut body = response.into_body();
+        let mut bytes = Vec::new();
+        while let Some(chunk) = body.data().await {
+            bytes.extend_from_slice(&chunk.unwrap());
+        }
+        (status, serde_json::from_slice(&bytes).unwrap())
+    }
+
+    #[tokio::test]
+    async fn set_sends_to_all_robots() {
+        let neato = neato(FakeBackend::new(&["Vacuum", "Botvac2"]), fake::settings()).await;
+
+        let (status, body) = post(&neato, "set", "sendToBase").await;
+
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(body["robots"], serde_json::json!(["Vacuum", "Botvac2"]));
+    }
+
+    #[tokio::test]
+    async fn group_sends_to_its_robots() {
+        let mut settings = fake::settings();
+        settings.groups.insert(
+            String::from("upstairs"),
+            GroupSettings::Robots(vec![String::from("Botvac2")]),
+        );
+        let neato = neato(FakeBackend::new(&["Vacuum", "Botvac2"]), settings).await;
+
+        let (status, body) = post(&neato, "upstairs", "sendToBase").await;
+
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(body["robots"], serde_json::json!(["Botvac2"]));
+    }
+
+    #[tokio::test]
+    async fn unknown_target_is_not_found() {
+        let neato = neato(FakeBackend::new(&["Vacuum"]), fake::settings()).await;
+
+        let (status, body) = post(&neato, "downstairs", "sendToBase").await;
+
+        assert_eq!(status, StatusCode::NOT_FOUND);
+        assert_eq!(
+            body["error"],
+            "No robot or group found with id \"downstairs\""
+        );
+    }
+
+    #[tokio::test]
+    async fn failures_are_reported_together() {
+        let backend = FakeBackend::new(&["Vacuum", "Botvac2", "Kitchen"]);
+        backend
+            .failures
+            .lock()
+            .unwrap()
+            .push_back(ErrorKind::RobotOffline);
+        let neato = neato(backend, fake::settings()).await;
+
+        let (status, body) = post(&neato, "set", "sendToBase").await;
+
+        assert_eq!(status, StatusCode::BAD_GATEWAY);
+        assert_eq!(body["robots"], serde_json::json!(["Botvac2", "Kitchen"]));
+        assert_eq!(body["failed"], serde_json::json!(["Vacuum"]));
+        assert!(body["error"].as_str().unwrap().contains("Vacuum"));
+    }
+
+    /// A port that was free a moment ago
+    fn free_port() -> u16 {
+        std::net::TcpListener::bind("127.0.0.1:0")
+            .unwrap()
+            .local_addr()
+            .unwrap()
+            .port()
+    }
+
+    /// Status line of `GET /robots`, None if nothing listens on the port
+    async fn get_robots(port: u16) -> Option<String> {
+        use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+        let mut stream = tokio::net::TcpStream::connect(("127.0.0.1", port))
+            .await
+            .ok()?;
+        stream
+            .write_all(b"GET /robots HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\n\r\n")
+            .await
+            .unwrap();
+        let mut response = String::new();
+        stream.read_to_string(&mut response).await.unwrap();
+        response.lines().next().map(str::to_string)
+    }
+
+    #[tokio::test]
+    async fn listener_is_restarted_when_the_settings_change() {
+        let mut settings = fake::settings();
+        settings.http.enabled = true;
+        settings.http.port = free_port();
+        let neato = neato(FakeBackend::new(&["Vacuum"]), settings.clone()).await;
+        let (tx, rx) = tokio::sync::watch::channel(settings.clone());
+        init(rx, neato).await.unwrap();
+        let old_port = settings.http.port;
+        assert_eq!(
+            get_robots(old_port).await.as_deref(),
+            Some("HTTP/1.1 200 OK")
+        );
+
+        settings.http.port = free_port();
+        tx.send(settings.clone()).unwrap();
+        let mut status = None;
+        for _ in 0..50 {
+            status = get_robots(settings.http.port).await;
+            if status.is_some() {
+                break;
+            }
+            tokio::time::sleep(Duration::from_millis(20)).await;
+        }
+
+        assert_eq!(status.as_deref(), Some("HTTP/1.1 200 OK"));
+        assert_eq!(get_robots(old_port).await, None);
+    }
+}
@@ -1,29 +1,72 @@
 extern crate log;
 extern crate pretty_env_logger;
 
+mod backend;
+mod cli;
+mod connection;
+mod events;
+mod homie;
+mod http;
 mod mqtt;
 mod neato;
 mod neato_types;
+mod reload;
+mod rules;
+mod scheduler;
+mod secret;
 mod settings;
+mod topics;
+mod validation;
 
+use backend::{RobotBackend, SelectedBackend};
+use clap::Parser;
+use cli::{Cli, Command};
 use color_eyre::Result;
 use neato::Neato;
 
-use crate::settings::read_settings;
+use crate::settings::{read_settings, Settings};
 
-#[tokio::main]
-async fn main() -> Result<()> {
-    color_eyre::install()?;
-    pretty_env_logger::init();
+async fn run<B: RobotBackend>(
+    backend: B,
+    settings: Settings,
+    config: Option<String>,
+) -> Result<()> {
+    let (settings_tx, settings_rx) = tokio::sync::watch::channel(settings.clone());
 
-    let settings = read_settings()?;
     // let mqtt_client = mk_mqtt_client(&settings).await?;
-    let mqtt_client = mqtt::init(&settings.mqtt.clone()).await?;
-    let _neato = Neato::new(mqtt_client, &settings.neato.clone())
+    let mqtt_client = mqtt::init(settings_rx.clone()).await?;
+    let neato = Neato::new(backend, mqtt_client.clone(), settings_rx.clone())
         .init()
         .await?;
+    http::init(settings_rx.clone(), neato.clone()).await?;
+    scheduler::Scheduler::new(neato.clone(), mqtt_client, settings_rx).init();
+
+    reload::watch(config, settings_tx);
 
     tokio::signal::ctrl_c().await?;
 
     Ok(())
 }
+
+async fn dispatch<B: RobotBackend>(backend: B, cli: Cli, settings: Settings) -> Result<()> {
+    match cli.command.unwrap_or(Command::Run) {
+        Command::Run => run(backend, settings, cli.config).await,
+        Command::ListRobots => cli::list_robots(&backend, &settings, cli.output).await,
+        Command::State { robot } => cli::state(&backend, &settings, cli.output, &robot).await,
+        Command::Send { robot, action } => {
+            cli::send(&backend, &settings, cli.output, &robot, &action).await
+        }
+        Command::CheckConfig => cli::check_config(&settings, cli.output),
+    }
+}
+
+#[tokio::main]
+async fn main() -> Result<()> {
+    color_eyre::install()?;
+    pretty_env_logger::init();
+
+    let cli = Cli::parse();
+    let settings = read_settings(cli.config.as_deref())?;
+
+    dispatch(SelectedBackend::new(&settings), cli, settings).await
+}
@@ -1,13 +1,25 @@
 use eyre::Result;
-use rand::{distributions::Alphanumeric, Rng};
-use rumqttc::{AsyncClient, ConnectionError, Event, MqttOptions, Publish, QoS};
 use serde::{Deserialize, Serialize};
-use std::{sync::Arc, time::Duration};
-use tokio::{sync::watch::Receiver, task};
+use std::{
+    collections::HashMap,
+    sync::{Arc, Mutex, RwLock},
+    time::Duration,
+};
+use tokio::{
+    sync::{mpsc, watch::Receiver, Mutex as AsyncMutex},
+    task,
+};
 
-use log::{debug, error};
+use log::{debug, error, info, warn};
 
-use crate::{neato::RobotCmd, settings::MqttSettings};
+use crate::{
+    connection::{Client, Connection, Message, Notification, Properties, Qos, ResponseTo, Will},
+    homie,
+    neato::{CommandFailures, RobotCmd, PLAIN_COMMANDS},
+    neato_types::HouseCleaningParams,
+    settings::{MqttSettings, Settings},
+    topics::{bridge_values, fill_topic, Placeholder, TopicStyle, TopicTemplate, TopicValues},
+};
 
 #[derive(Clone, Deserialize, Serialize, Debug)]
 pub struct MqttSetMessage {
@@ -20,108 +32,566 @@ pub struct SendAction {
     pub action: RobotCmd,
 }
 
+/// A command received over MQTT
+#[derive(Clone, Debug)]
+pub struct MqttCommand {
+    pub action: SendAction,
+    /// Placeholder values of the topic the command came in on
+    pub values: TopicValues,
+    /// Set if the sender asked for the result with an MQTT 5 `ResponseTopic`
+    pub response_to: Option<ResponseTo>,
+}
+
+/// Published to the `ResponseTopic` of a command
+#[derive(Serialize, Debug)]
+struct CommandResult<'a> {
+    ok: bool,
+    /// Robots that got the command
+    robots: &'a [String],
+    /// Robots that didn't, if the command was for several
+    #[serde(skip_serializing_if = "<[_]>::is_empty")]
+    failed: Vec<&'a str>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    error: Option<String>,
+}
+
+/// Published to `{topic}/errors` when a command on a set topic can't be parsed
+#[derive(Serialize, Debug)]
+struct CommandError<'a> {
+    topic: &'a str,
+    payload: String,
+    error: String,
+    accepted: String,
+}
+
+/// Connection that holds the will of a robot
+struct WillConnection {
+    will: Will,
+    /// Settings the connection was made with
+    settings: MqttSettings,
+    client: Client,
+}
+
 #[derive(Clone)]
 pub struct MqttClient {
-    pub client: AsyncClient,
-    pub rx: Receiver<Option<SendAction>>,
-    pub topic: String,
-    pub set_topic: String,
-    pub settings: MqttSettings,
+    /// Replaced when the connection is made again for new settings
+    client: Arc<RwLock<Client>>,
+    /// Commands in the order they were received, none are dropped while one is handled
+    pub rx: Arc<AsyncMutex<mpsc::UnboundedReceiver<MqttCommand>>>,
+    settings: Receiver<Settings>,
+    /// Last value received on each schedule condition topic
+    conditions: Arc<Mutex<HashMap<String, String>>>,
+    /// Robot `{id}` of each Homie device id
+    homie_devices: Arc<Mutex<HashMap<String, String>>>,
+    /// Connection of each robot `{id}` that holds its will
+    wills: Arc<Mutex<HashMap<String, WillConnection>>>,
 }
 
-pub fn get_id_from_topic(topic: &String, set_topic: &str) -> Result<String> {
-    if let Some((start, end)) = set_topic.split_once("{id}") {
-        Ok(topic.replace(start, "").replace(end, ""))
-    } else {
-        error!("Could not get id from topic: '{}'", topic);
-        Err(eyre::eyre!("Could not get id from topic: '{}'", topic))
+impl MqttClient {
+    /// Client of the current connection
+    pub fn client(&self) -> Client {
+        self.client.read().unwrap().clone()
+    }
+
+    /// Current MQTT settings, these may change when the settings file is reloaded
+    pub fn settings(&self) -> MqttSettings {
+        self.settings.borrow().mqtt.clone()
+    }
+
+    /// Last value received on a schedule `condition_topic`, if any
+    pub fn condition(&self, topic: &str) -> Option<String> {
+        self.conditions.lock().unwrap().get(topic).cloned()
+    }
+
+    /// Remembers the robot behind a Homie device id, true if the device is new
+    pub fn register_homie_device(&self, device: &str, robot_id: &str) -> bool {
+        let mut devices = self.homie_devices.lock().unwrap();
+        match devices.insert(device.to_string(), robot_id.to_string()) {
+            None => true,
+            Some(previous) if previous != robot_id => {
+                warn!(
+                    "Robots {} and {} have the same Homie device id {}",
+                    previous, robot_id, device
+                );
+                true
+            }
+            Some(_) => false,
+        }
+    }
+
+    /// Keeps a connection that leaves `will` with the broker for the robot, so that the robot
+    /// is reported offline if the bridge goes away. MQTT has one will per connection, so each
+    /// robot gets its own, as `{mqtt.id}-{device id}`. A connection with an outdated will or
+    /// broker is disconnected, which discards its will.
+    pub fn set_will(&self, robot_id: &str, will: Will) {
+        let settings = self.settings();
+        let mut wills = self.wills.lock().unwrap();
+        if let Some(current) = wills.get(robot_id) {
+            if current.will == will && !connection_changed(&current.settings, &settings) {
+                return;
+            }
+        }
+        let client_id = format!("{}-{}", settings.id, homie::device_id(robot_id));
+        let (client, connection) = Client::with_will(&settings, client_id, &will);
+        task::spawn(poll_until_disconnected(connection));
+        let current = WillConnection {
+            will,
+            settings,
+            client,
+        };
+        if let Some(previous) = wills.insert(robot_id.to_string(), current) {
+            if let Err(err) = previous.client.try_disconnect() {
+                error!("Could not disconnect the will of {}: {}", robot_id, err);
+            }
+        }
+    }
+
+    /// Publishes the result of a command to the topic its sender asked for
+    pub async fn respond(&self, response_to: &ResponseTo, result: &Result<Vec<String>>) {
+        respond(&self.client(), &self.settings(), response_to, result).await
+    }
+
+    /// Client that never connects, for tests that don't publish
+    #[cfg(test)]
+    pub fn disconnected(settings: Receiver<Settings>) -> MqttClient {
+        let (client, _connection) = Client::new(&settings.borrow().mqtt);
+        let (_tx, rx) = mpsc::unbounded_channel();
+        MqttClient {
+            client: Arc::new(RwLock::new(client)),
+            rx: Arc::new(AsyncMutex::new(rx)),
+            settings,
+            conditions: Arc::new(Mutex::new(HashMap::new())),
+            homie_devices: Arc::new(Mutex::new(HashMap::new())),
+            wills: Arc::new(Mutex::new(HashMap::new())),
+        }
     }
 }
 
-struct NotificationResult {
-    message: Option<Publish>,
+/// Polls a connection until the disconnect queued with its client is sent, for will
+/// connections and connections that were replaced
+async fn poll_until_disconnected(mut connection: Connection) {
+    loop {
+        match connection.poll().await {
+            Ok(Notification::Disconnected) => return,
+            Ok(_) => {}
+            Err(e) => {
+                debug!("MQTT error of a closing or will connection: {:?}", e);
+                tokio::time::sleep(Duration::from_secs(1)).await;
+            }
+        }
+    }
 }
 
-async fn handle_notification(
-    client: &AsyncClient,
-    notification: Result<Event, ConnectionError>,
+/// Whether the connection has to be made again for the new settings
+fn connection_changed(old: &MqttSettings, new: &MqttSettings) -> bool {
+    old.version != new.version
+        || old.channel_capacity != new.channel_capacity
+        || old.host != new.host
+        || old.port != new.port
+        || old.id != new.id
+        || old.keep_alive != new.keep_alive
+        || old.clean_session != new.clean_session
+        || old.session_expiry != new.session_expiry
+        || old.inflight != new.inflight
+}
+
+async fn respond(
+    client: &Client,
     mqtt_settings: &MqttSettings,
-) -> Result<NotificationResult> {
-    debug!("Notification: {:?}", notification);
-    match notification? {
-        rumqttc::Event::Incoming(rumqttc::Packet::ConnAck(_)) => {
-            client
-                .subscribe(mqtt_settings.get_set_topic_with_wildcard(), QoS::AtMostOnce)
-                .await?;
+    response_to: &ResponseTo,
+    result: &Result<Vec<String>>,
+) {
+    let failures = result
+        .as_ref()
+        .err()
+        .and_then(|err| err.downcast_ref::<CommandFailures>());
+    let (robots, error) = match (result, failures) {
+        (Ok(robots), _) => (robots.as_slice(), None),
+        (Err(err), Some(failures)) => (failures.succeeded.as_slice(), Some(err.to_string())),
+        (Err(err), None) => (&[][..], Some(err.to_string())),
+    };
+    let payload = CommandResult {
+        ok: error.is_none(),
+        robots,
+        failed: failures.map_or_else(Vec::new, |f| {
+            f.failed.iter().map(|(id, _)| id.as_str()).collect()
+        }),
+        error,
+    };
+    let properties = Properties {
+        correlation_data: response_to.correlation_data.clone(),
+        ..Properties::default()
+    };
+    let published = match serde_json::to_string(&payload) {
+        Ok(payload) => {
             client
-                .subscribe(mqtt_settings.get_topic_with_id_as_set(), QoS::AtMostOnce)
-                .await?;
-            // return Ok(NotificationResult{message: None})
+                .publish_with_properties(
+                    &response_to.topic,
+                    mqtt_settings.qos.commands.into(),
+                    false,
+                    payload,
+                    properties,
+                )
+                .await
+        }
+        Err(err) => Err(err.into()),
+    };
+    if let Err(err) = published {
+        error!(
+            "Could not publish the command result to {}: {}",
+            response_to.topic, err
+        );
+    }
+}
+
+fn condition_topics(settings: &Settings) -> Vec<String> {
+    let mut topics: Vec<String> = settings
+        .schedules
+        .iter()
+        .filter_map(|s| s.condition_topic.clone())
+        .collect();
+    topics.sort();
+    topics.dedup();
+    topics
+}
+
+/// Topics to subscribe to: the set and broadcast topics, also with a command as an extra
+/// level, and the schedule condition topics
+pub fn subscriptions(settings: &Settings) -> Vec<String> {
+    let mut topics = Vec::new();
+    let broadcast = settings.mqtt.get_broadcast_topic();
+    match TopicTemplate::parse(&settings.mqtt.set_topic) {
+        Ok(set_topic) => {
+            let subscription = set_topic.subscription(&bridge_values(&settings.mqtt));
+            topics.push(format!("{}/+", subscription));
+            topics.push(subscription);
+        }
+        Err(e) => error!("Invalid set topic {}: {}", settings.mqtt.set_topic, e),
+    }
+    topics.push(format!("{}/+", broadcast));
+    topics.push(broadcast);
+    if settings.mqtt.topic_style == TopicStyle::Homie {
+        topics.push(homie::SET_SUBSCRIPTION.to_string());
+    }
+    topics.extend(condition_topics(settings));
+    topics
+}
+
+/// Placeholder values, with `set` as `{id}` for the broadcast topic, and the command level of
+/// a message on one of the set topics such as `{id}/set/dock`. None if the topic is not a set
+/// topic of this bridge, or a subtopic that isn't a command, e.g. the state topic when
+/// `set_topic` is a prefix of `topic`.
+fn route<'a>(
+    topic: &'a str,
+    mqtt_settings: &MqttSettings,
+) -> Option<(TopicValues, Option<&'a str>)> {
+    let known = bridge_values(mqtt_settings);
+    let broadcast = mqtt_settings.get_broadcast_topic();
+    let broadcast_values = || {
+        let mut values = known.clone();
+        values.insert(Placeholder::Id, String::from("set"));
+        values
+    };
+    if topic == broadcast {
+        return Some((broadcast_values(), None));
+    }
+    let is_command = |action: &&str| RobotCmd::from_name(action).is_some();
+    if let Some(action) = topic
+        .strip_prefix(broadcast.as_str())
+        .and_then(|rest| rest.strip_prefix('/'))
+        .filter(|action| !action.contains('/'))
+    {
+        return is_command(&action).then(|| (broadcast_values(), Some(action)));
+    }
+    let set_topic = TopicTemplate::parse(&mqtt_settings.set_topic).ok()?;
+    if let Some(values) = set_topic.match_values(topic, &known) {
+        return Some((values, None));
+    }
+    let (topic, action) = topic.rsplit_once('/')?;
+    set_topic
+        .match_values(topic, &known)
+        .map(|values| (values, Some(action)))
+        .filter(|_| is_command(&action))
+}
+
+/// Where commands that can't be parsed are reported, None if `topic` has placeholders that
+/// the set topic doesn't
+fn errors_topic(mqtt_settings: &MqttSettings, values: &TopicValues) -> Option<String> {
+    fill_topic(&mqtt_settings.topic, values).map(|topic| format!("{}/errors", topic))
+}
+
+/// The payloads accepted on the set topics, for error messages
+fn accepted_formats(mqtt_settings: &MqttSettings) -> String {
+    let names: Vec<&str> = PLAIN_COMMANDS.iter().map(|(name, _)| *name).collect();
+    format!(
+        "JSON like {{\"action\": \"startCleaning\"}}, a plain command ({}) or any payload on {}/<command>",
+        names.join(", "),
+        mqtt_settings.set_topic
+    )
+}
+
+/// Command of a message on a set topic. Without an action topic the payload is either JSON
+/// like `{"action": "startCleaning"}` or a plain command such as `dock`. On an action topic
+/// the payload is ignored, except that `start` takes cleaning parameters as a JSON object.
+fn parse_command(action: Option<&str>, payload: &[u8]) -> Result<RobotCmd, String> {
+    let text = String::from_utf8_lossy(payload);
+    let text = text.trim();
+    if let Some(action) = action {
+        let cmd =
+            RobotCmd::from_name(action).ok_or_else(|| format!("Unknown command {:?}", action))?;
+        if cmd == RobotCmd::StartCleaning && text.starts_with('{') {
+            return serde_json::from_str::<HouseCleaningParams>(text)
+                .map(RobotCmd::StartCleaningWith)
+                .map_err(|e| format!("Invalid cleaning parameters: {}", e));
+        }
+        return Ok(cmd);
+    }
+    if text.starts_with('{') {
+        return serde_json::from_str::<MqttSetMessage>(text)
+            .map(|message| message.action)
+            .map_err(|e| format!("Invalid JSON command: {}", e));
+    }
+    // Also accept a JSON string such as `"dock"`
+    let name = text
+        .strip_prefix('"')
+        .and_then(|name| name.strip_suffix('"'))
+        .unwrap_or(text);
+    RobotCmd::from_name(name).ok_or_else(|| format!("Unknown command {:?}", text))
+}
+
+/// Reports a command that couldn't be parsed, to its MQTT 5 `ResponseTopic` if it has one
+fn report_parse_error(
+    client: &Client,
+    mqtt_settings: &MqttSettings,
+    values: &TopicValues,
+    msg: Message,
+    error: String,
+) {
+    let accepted = accepted_formats(mqtt_settings);
+    let client = client.clone();
+    let mqtt_settings = mqtt_settings.clone();
+    let topic = errors_topic(&mqtt_settings, values);
+    // Published from a task, the event loop must keep polling to make room in the channel
+    task::spawn(async move {
+        if let Some(response_to) = &msg.response_to {
+            let result = Err(eyre::eyre!("{}, expected {}", error, accepted));
+            respond(&client, &mqtt_settings, response_to, &result).await;
+            return;
+        }
+        let Some(topic) = topic else {
+            return;
+        };
+        let payload = CommandError {
+            topic: &msg.topic,
+            payload: String::from_utf8_lossy(&msg.payload).to_string(),
+            error,
+            accepted,
+        };
+        let published = match serde_json::to_string(&payload) {
+            Ok(payload) => {
+                client
+                    .publish(&topic, mqtt_settings.qos.commands.into(), false, payload)
+                    .await
+            }
+            Err(err) => Err(err.into()),
+        };
+        if let Err(err) = published {
+            error!("Could not publish the command error to {}: {}", topic, err);
+        }
+    });
+}
+
+/// Unsubscribes from `old` and then subscribes to `new` from a task. The requests wait for
+/// room in the channel of the connection, which only the event loop makes.
+fn resubscribe(client: &Client, old: Vec<String>, new: Vec<String>, qos: Qos) {
+    let client = client.clone();
+    task::spawn(async move {
+        for topic in old {
+            if let Err(err) = client.unsubscribe(&topic).await {
+                error!("Could not unsubscribe from {}: {}", topic, err);
+            }
+        }
+        for topic in new {
+            if let Err(err) = client.subscribe(&topic, qos.into()).await {
+                error!("Could not subscribe to {}: {}", topic, err);
+            }
         }
-        rumqttc::Event::Incoming(rumqttc::Packet::Publish(msg)) => {
-            return Ok(NotificationResult { message: Some(msg) })
+    });
+}
+
+struct NotificationResult {
+    message: Option<Message>,
+}
+
+fn handle_notification(
+    client: &Client,
+    notification: Result<Notification>,
+    settings: &Settings,
+) -> Result<NotificationResult> {
+    match notification? {
+        Notification::ConnAck => {
+            resubscribe(
+                client,
+                Vec::new(),
+                subscriptions(settings),
+                settings.mqtt.qos.commands,
+            );
         }
-        _ => {}
+        Notification::Message(msg) => return Ok(NotificationResult { message: Some(msg) }),
+        Notification::Disconnected | Notification::Other => {}
     }
 
     Ok(NotificationResult { message: None })
-    // Err(eyre::eyre!("Could not get message"))
 }
 
-pub async fn init(mqtt_settings: &MqttSettings) -> Result<MqttClient> {
-    let random_string: String = rand::thread_rng()
-        .sample_iter(&Alphanumeric)
-        .take(8)
-        .map(char::from)
-        .collect();
+/// Applies changed MQTT settings to the running connection. Topic changes are applied by
+/// resubscribing. For other changes the client and connection are made again, the old
+/// connection is closed once the requests queued before it are sent.
+fn apply_settings(
+    client: &RwLock<Client>,
+    connection: &mut Connection,
+    old: &Settings,
+    new: &Settings,
+) {
+    let (old_mqtt, new_mqtt) = (&old.mqtt, &new.mqtt);
+    if connection_changed(old_mqtt, new_mqtt) {
+        info!(
+            "MQTT connection settings changed, reconnecting to {}:{}",
+            new_mqtt.host, new_mqtt.port
+        );
+        // Subscriptions are made again on ConnAck
+        let (new_client, new_connection) = Client::new(new_mqtt);
+        let old_client = std::mem::replace(&mut *client.write().unwrap(), new_client);
+        let old_connection = std::mem::replace(connection, new_connection);
+        if let Err(err) = old_client.try_disconnect() {
+            error!("Could not disconnect the old MQTT connection: {}", err);
+        }
+        task::spawn(poll_until_disconnected(old_connection));
+        return;
+    }
 
-    let mut options = MqttOptions::new(
-        format!("{}-{}", mqtt_settings.id.clone(), random_string),
-        mqtt_settings.host.clone(),
-        mqtt_settings.port,
-    );
-    options.set_keep_alive(Duration::from_secs(5));
-    let (client, mut eventloop) = AsyncClient::new(options, 10);
+    let old_topics = subscriptions(old);
+    let new_topics = subscriptions(new);
+    let qos_changed = old_mqtt.qos.commands != new_mqtt.qos.commands;
+    if old_topics != new_topics || qos_changed {
+        info!("MQTT topics changed, subscribing to {:?}", new_topics);
+        let removed = old_topics
+            .iter()
+            .filter(|t| !new_topics.contains(t))
+            .cloned()
+            .collect();
+        // Subscribing again replaces the QoS of an existing subscription
+        let added = new_topics
+            .iter()
+            .filter(|t| qos_changed || !old_topics.contains(t))
+            .cloned()
+            .collect();
+        resubscribe(
+            &client.read().unwrap(),
+            removed,
+            added,
+            new_mqtt.qos.commands,
+        );
+    }
+}
 
-    let subscribe_settings = mqtt_settings.clone();
-    let subscribe_client = client.clone();
+pub async fn init(settings: Receiver<Settings>) -> Result<MqttClient> {
+    let mut current_settings = settings.borrow().clone();
+    let (client, mut connection) = Client::new(&current_settings.mqtt);
+    let client = Arc::new(RwLock::new(client));
+    let conditions = Arc::new(Mutex::new(HashMap::new()));
+    let received_conditions = Arc::clone(&conditions);
+    let homie_devices = Arc::new(Mutex::new(HashMap::new()));
+    let known_devices = Arc::clone(&homie_devices);
 
-    let config_clone = Arc::new(mqtt_settings.clone());
+    let subscribe_client = Arc::clone(&client);
+    let mut settings_rx = settings.clone();
+    let mut settings_open = true;
 
-    let (tx, rx) = tokio::sync::watch::channel(None);
+    // Unbounded, so that the event loop never waits for a command to be handled
+    let (tx, rx) = mpsc::unbounded_channel();
 
-    // Listen on set_topic, for example `home/devices/neato/{id}/set`
+    // Listen on set_topic, for example `home/devices/neato/{id}/set`, and the broadcast topic
     task::spawn(async move {
         loop {
-            let notification = eventloop.poll().await;
+            let notification = tokio::select! {
+                notification = connection.poll() => notification,
+                changed = settings_rx.changed(), if settings_open => {
+                    if changed.is_err() {
+                        settings_open = false;
+                        continue;
+                    }
+                    let new_settings = settings_rx.borrow_and_update().clone();
+                    apply_settings(
+                        &subscribe_client,
+                        &mut connection,
+                        &current_settings,
+                        &new_settings,
+                    );
+                    current_settings = new_settings;
+                    continue;
+                }
+            };
 
-            let id = subscribe_settings.id.clone();
-            let config_clone = Arc::clone(&config_clone);
+            let id = current_settings.mqtt.id.clone();
 
-            let res =
-                handle_notification(&subscribe_client, notification, &subscribe_settings.clone())
-                    .await;
+            let client = subscribe_client.read().unwrap().clone();
+            let res = handle_notification(&client, notification, &current_settings);
 
             match res {
                 Ok(NotificationResult { message: Some(msg) }) => {
                     debug!("Reveiced MQTT Publish for topic: {:?}", &msg.topic);
-                    let id = get_id_from_topic(&msg.topic, &config_clone.set_topic).unwrap();
+                    if condition_topics(&current_settings).contains(&msg.topic) {
+                        let value = String::from_utf8_lossy(&msg.payload).trim().to_string();
+                        debug!("Condition {} is now {:?}", msg.topic, value);
+                        received_conditions
+                            .lock()
+                            .unwrap()
+                            .insert(msg.topic.clone(), value);
+                        continue;
+                    }
+                    if current_settings.mqtt.topic_style == TopicStyle::Homie {
+                        let payload = String::from_utf8_lossy(&msg.payload);
+                        if let Some((device, command)) = homie::parse_set(&msg.topic, &payload) {
+                            let id = known_devices.lock().unwrap().get(&device).cloned();
+                            match (id, command) {
+                                (Some(id), Ok(action)) => {
+                                    let command = MqttCommand {
+                                        action: SendAction { id, action },
+                                        values: TopicValues::new(),
+                                        response_to: msg.response_to,
+                                    };
+                                    tx.send(command).expect("Failed to send message");
+                                }
+                                (None, _) => error!("Unknown Homie device {}", device),
+                                (_, Err(e)) => error!("{}", e),
+                            }
+                            continue;
+                        }
+                    }
+                    let Some((values, action)) = route(&msg.topic, &current_settings.mqtt) else {
+                        debug!("Ignoring message on {}", msg.topic);
+                        continue;
+                    };
+                    let Some(id) = values.get(&Placeholder::Id).cloned() else {
+                        continue;
+                    };
                     debug!("Id is: {:?}", id);
-                    let payload: MqttSetMessage = match serde_json::from_slice(&msg.payload) {
-                        Ok(pl) => pl,
+                    let action = match parse_command(action, &msg.payload) {
+                        Ok(action) => action,
                         Err(e) => {
-                            error!("Could not parse JSON payload: {:?}", e);
+                            error!("Could not parse the command on {}: {}", msg.topic, e);
+                            report_parse_error(&client, &current_settings.mqtt, &values, msg, e);
                             continue;
                         }
                     };
-                    debug!("Payload is: {:?}", payload);
-                    let device = {
-                        SendAction {
-                            id,
-                            action: payload.action,
-                        }
+                    debug!("Action is: {:?}", action);
+                    let command = MqttCommand {
+                        action: SendAction { id, action },
+                        values,
+                        response_to: msg.response_to,
                     };
-                    tx.send(Some(device)).expect("Failed to send message");
+                    tx.send(command).expect("Failed to send message");
                 }
                 Err(e) => {
                     error!(
@@ -135,15 +605,175 @@ pub async fn init(mqtt_settings: &MqttSettings) -> Result<MqttClient> {
         }
     });
 
-    // listen on the topic set, for example `home/devices/neato/set`
-    // If an action is received here, the command should affect all devices
-    // TODO: implement this
-
     Ok(MqttClient {
         client,
-        rx,
-        topic: mqtt_settings.topic.clone(),
-        set_topic: mqtt_settings.set_topic.clone(),
-        settings: mqtt_settings.clone(),
+        rx: Arc::new(AsyncMutex::new(rx)),
+        settings,
+        conditions,
+        homie_devices,
+        wills: Arc::new(Mutex::new(HashMap::new())),
     })
 }
+
+#[cfg(test)]
+mod tests {
+    use rumqttc::{v5, QoS};
+
+    use super::*;
+
+    #[test]
+    fn plain_and_json_payloads_are_parsed() {
+        assert_eq!(
+            parse_command(None, br#"{"action": "sendToBase"}"#),
+            Ok(RobotCmd::SendToBase)
+        );
+        assert_eq!(parse_command(None, b"dock\n"), Ok(RobotCmd::SendToBase));
+        assert_eq!(parse_command(None, b"Start"), Ok(RobotCmd::StartCleaning));
+        assert_eq!(
+            parse_command(None, b"\"pause_cleaning\""),
+            Ok(RobotCmd::PauseCleaning)
+        );
+        assert!(parse_command(None, b"fly").is_err());
+        assert!(parse_command(None, br#"{"action": "fly"}"#).is_err());
+    }
+
+    #[test]
+    fn action_topics_ignore_the_payload() {
+        assert_eq!(
+            parse_command(Some("stop"), b"ON"),
+            Ok(RobotCmd::StopCleaning)
+        );
+        assert_eq!(
+            parse_command(Some("start"), br#"{"mode": 2}"#),
+            Ok(RobotCmd::StartCleaningWith(HouseCleaningParams {
+                mode: 2,
+                ..HouseCleaningParams::default()
+            }))
+        );
+        assert!(parse_command(Some("fly"), b"").is_err());
+    }
+
+    #[test]
+    fn set_topics_are_routed() {
+        let mut mqtt = crate::backend::fake::settings().mqtt;
+        let routed = |topic: &'static str, mqtt: &MqttSettings| {
+            route(topic, mqtt).map(|(values, action)| {
+                (values[&Placeholder::Id].clone(), action.map(str::to_string))
+            })
+        };
+        let robot =
+            |id: &str, action: Option<&str>| Some((id.to_string(), action.map(str::to_string)));
+
+        assert_eq!(
+            routed("home/devices/neato/Vacuum/set", &mqtt),
+            robot("Vacuum", None)
+        );
+        assert_eq!(
+            routed("home/devices/neato/Vacuum/set/dock", &mqtt),
+            robot("Vacuum", Some("dock"))
+        );
+        assert_eq!(routed("home/devices/neato/set", &mqtt), robot("set", None));
+        assert_eq!(
+            routed("home/devices/neato/set/dock", &mqtt),
+            robot("set", Some("dock"))
+        );
+        assert_eq!(routed("home/devices/neato/Vacuum", &mqtt), None);
+
+        mqtt.set_topic = String::from("neato/{id}/set");
+        mqtt.broadcast_topic = Some(String::from("neato/all"));
+        assert_eq!(routed("neato/neato/set", &mqtt), robot("neato", None));
+        assert_eq!(routed("neato/all", &mqtt), robot("set", None));
+        assert_eq!(routed("home/devices/neato/set", &mqtt), None);
+
+        mqtt.set_topic = String::from("{bridge_id}/{model}/{id}/set");
+        assert_eq!(
+            routed("neato-mqtt-test/BotVacD7Connected/Vacuum/set", &mqtt),
+            robot("Vacuum", None)
+        );
+        // Commands for another bridge on the same broker
+        assert_eq!(routed("other/BotVacD7Connected/Vacuum/set", &mqtt), None);
+    }
+
+    #[test]
+    fn subtopics_that_are_not_commands_are_ignored() {
+        let mut mqtt = crate::backend::fake::settings().mqtt;
+        mqtt.topic = String::from("neato/{id}/state");
+        mqtt.set_topic = String::from("neato/{id}");
+        mqtt.broadcast_topic = Some(String::from("neato/all"));
+
+        assert!(route("neato/Vacuum/state", &mqtt).is_none());
+        assert!(route("neato/all/errors", &mqtt).is_none());
+        assert!(route("neato/Vacuum/dock", &mqtt).is_some());
+        assert!(route("neato/Vacuum", &mqtt).is_some());
+    }
+
+    #[tokio::test]
+    async fn connack_does_not_wait_for_room_in_the_queue() {
+        let mut settings = crate::backend::fake::settings();
+        settings.mqtt.channel_capacity = 1;
+        let (client, _connection) = Client::new(&settings.mqtt);
+        if let Client::V311(client) = &client {
+            client
+                .try_publish("neato/Vacuum", QoS::AtMostOnce, false, "full")
+                .unwrap();
+        }
+
+        // Called from the event loop, which is the only one to make room in the queue
+        let handled = handle_notification(&client, Ok(Notification::ConnAck), &settings);
+
+        assert!(matches!(handled, Ok(NotificationResult { message: None })));
+    }
+
+    #[tokio::test]
+    async fn connection_is_made_again_for_new_settings() {
+        let old = crate::backend::fake::settings();
+        let (client, mut connection) = Client::new(&old.mqtt);
+        let client = RwLock::new(client);
+
+        let mut new = old.clone();
+        new.mqtt.version = crate::connection::MqttVersion::V5;
+        new.mqtt.channel_capacity = 1;
+        new.mqtt.host = String::from("broker.local");
+        apply_settings(&client, &mut connection, &old, &new);
+
+        let Connection::V5(eventloop) = &connection else {
+            panic!("expected an MQTT 5 connection");
+        };
+        assert_eq!(
+            eventloop.options.broker_address(),
+            (String::from("broker.local"), 1883)
+        );
+        let Client::V5(client) = client.read().unwrap().clone() else {
+            panic!("expected an MQTT 5 client");
+        };
+        client
+            .try_publish("neato/Vacuum", v5::mqttbytes::QoS::AtMostOnce, false, "1")
+            .unwrap();
+        // The channel of the new connection holds one request
+        assert!(client
+            .try_publish("neato/Vacuum", v5::mqttbytes::QoS::AtMostOnce, false, "2")
+            .is_err());
+    }
+
+    #[tokio::test]
+    async fn topic_changes_keep_the_connection() {
+        let old = crate::backend::fake::settings();
+        let (client, mut connection) = Client::new(&old.mqtt);
+        let client = RwLock::new(client);
+        // Marks the connection, a new one would have the keep alive of the settings
+        if let Connection::V311(eventloop) = &mut connection {
+            eventloop
+                .mqtt_options
+                .set_keep_alive(Duration::from_secs(42));
+        }
+
+        let mut new = old.clone();
+        new.mqtt.set_topic = String::from("neato/{id}/set");
+        apply_settings(&client, &mut connection, &old, &new);
+
+        let Connection::V311(eventloop) = &connection else {
+            panic!("expected the MQTT 3.1.1 connection");
+        };
+        assert_eq!(eventloop.mqtt_options.keep_alive(), Duration::from_secs(42));
+    }
+}
@@ -1,69 +1,210 @@
 use std::{
+    collections::{HashMap, VecDeque},
     fmt,
-    sync::{Arc, Mutex},
+    future::Future,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Mutex, RwLock,
+    },
     time::Duration,
 };
 
-use tokio::sync::Mutex as AsyncMutex;
+use tokio::{
+    sync::{watch, Mutex as AsyncMutex, Semaphore},
+    task::{AbortHandle, JoinSet},
+    time::Instant,
+};
 
-use chrono::Utc;
+use chrono::{DateTime, Utc};
 use color_eyre::Result;
 use eyre::eyre;
-use hmac::{Hmac, Mac};
+use rand::Rng;
 use serde::{Deserialize, Serialize};
-use sha2::Sha256;
 
-use log::{debug, error, info};
+use log::{debug, error, info, warn};
 
-use crate::{mqtt::MqttClient, settings::NeatoSettings};
 use crate::{
-    mqtt::SendAction,
-    neato_types::{HouseCleaningParams, NeatoState, PublicRobot, Robot, RobotMessage},
+    backend::{CloudBackend, ErrorKind, RequestError, RobotBackend},
+    connection::{Properties, Will},
+    events::{self, Event, EventKind},
+    homie,
+    mqtt::MqttClient,
+    rules::Rules,
+    secret::Secret,
+    settings::{GroupSettings, MqttSettings, NeatoAccount, NeatoSettings, Settings},
+    topics::{robot_topic, robot_topic_id, robot_values, Placeholder, TopicStyle, TopicValues},
+};
+use crate::{
+    mqtt::{MqttCommand, SendAction},
+    neato_types::{
+        HouseCleaningParams, NeatoState, PublicRobot, Robot, RobotError, RobotMessage, RobotState,
+    },
 };
 
+/// Whether a robot started by a sequential group is cleaning or away from its base
+fn has_left(state: &NeatoState) -> bool {
+    matches!(state.state, RobotState::Busy | RobotState::Paused) || !state.details.is_docked
+}
+
+/// Whether a robot started by a sequential group has finished: stopped by an error, or back
+/// on its base after it `has_left` it. Right after the start it may still be docked and idle.
+fn is_done(state: &NeatoState, left: bool) -> bool {
+    state.state == RobotState::Error || (left && !has_left(state))
+}
+
 impl Robot {
+    pub fn to_public(&self) -> PublicRobot {
+        PublicRobot::from(self)
+    }
+
+    /// MQTT 5 user properties attached to the messages about the robot
+    fn user_properties(&self) -> Vec<(String, String)> {
+        let mut properties = vec![(String::from("serial"), self.serial.clone())];
+        if let Some(firmware) = &self.firmware {
+            properties.push((String::from("firmware"), firmware.clone()));
+        }
+        properties
+    }
+
     pub async fn publish(&self, mqtt_client: MqttClient) -> color_eyre::Result<()> {
-        let topic = mqtt_client
-            .topic
-            .clone()
-            .replace("{id}", self.name.as_str());
+        if mqtt_client.settings().topic_style == TopicStyle::Homie {
+            return self.publish_homie(&mqtt_client).await;
+        }
+
+        let topic = robot_topic(&mqtt_client.settings().topic, self, &mqtt_client.settings());
 
-        let v = serde_json::to_value(self).unwrap();
-        let public_robot: PublicRobot = serde_json::from_value(v).unwrap();
+        let public_robot = self.to_public();
+        let settings = mqtt_client.settings();
+        let properties = Properties {
+            message_expiry: (settings.state_expiry > 0).then_some(settings.state_expiry),
+            user_properties: self.user_properties(),
+            ..Properties::default()
+        };
         mqtt_client
-            .client
-            .publish(
-                topic,
-                rumqttc::QoS::AtMostOnce,
-                false,
+            .client()
+            .publish_with_properties(
+                &topic,
+                settings.qos.state.into(),
+                settings.retain.state,
                 serde_json::to_string(&public_robot)?,
+                properties,
             )
-            .await
-            .unwrap();
+            .await?;
+
+        // Retained by default, so that subscribers know right away whether the robot is
+        // reachable
+        let availability = if public_robot.online {
+            "online"
+        } else {
+            "offline"
+        };
+        let properties = Properties {
+            user_properties: self.user_properties(),
+            ..Properties::default()
+        };
+        let availability_topic = format!("{}/availability", topic);
+        mqtt_client
+            .client()
+            .publish_with_properties(
+                &availability_topic,
+                settings.qos.state.into(),
+                settings.retain.availability,
+                availability,
+                properties,
+            )
+            .await?;
+        mqtt_client.set_will(
+            &self.id,
+            Will {
+                topic: availability_topic,
+                payload: String::from("offline"),
+                retain: settings.retain.availability,
+            },
+        );
 
         Ok(())
     }
-}
 
-#[derive(Deserialize)]
-struct SessionsResponse {
-    access_token: String,
-}
+    /// Publishes the robot as a Homie device, with its attributes the first time
+    async fn publish_homie(&self, mqtt_client: &MqttClient) -> color_eyre::Result<()> {
+        let public_robot = self.to_public();
+        let qos = mqtt_client.settings().qos;
+        let mut messages = Vec::new();
+        if mqtt_client.register_homie_device(&homie::device_id(&self.id), &self.id) {
+            messages.extend(
+                homie::description(&public_robot)
+                    .into_iter()
+                    .map(|message| (message, qos.discovery)),
+            );
+        }
+        messages.extend(
+            homie::values(&public_robot)
+                .into_iter()
+                .map(|message| (message, qos.state)),
+        );
 
-#[derive(Serialize)]
-struct AuthBody {
-    email: String,
-    password: String,
-}
+        let properties = Properties {
+            user_properties: self.user_properties(),
+            ..Properties::default()
+        };
+        // Homie requires everything but commands to be retained
+        for ((topic, payload), qos) in messages {
+            mqtt_client
+                .client()
+                .publish_with_properties(topic, qos.into(), true, payload, properties.clone())
+                .await?;
+        }
+        let (topic, payload) = homie::will(&self.id);
+        mqtt_client.set_will(
+            &self.id,
+            Will {
+                topic,
+                payload,
+                retain: true,
+            },
+        );
+
+        Ok(())
+    }
 
-const BASE_URL: &str = "https://beehive.neatocloud.com";
+    /// Whether the robot is offline and was last tried less than `offline_poll_interval` ago
+    fn skip_offline_poll(&self, offline_poll_interval: u16) -> bool {
+        match (&self.offline_since, &self.last_error) {
+            (Some(_), Some(last_error)) => {
+                (Utc::now() - last_error.time).num_seconds() < offline_poll_interval as i64
+            }
+            _ => false,
+        }
+    }
 
-type HmacSha256 = Hmac<Sha256>;
+    /// Marks the robot as offline if the error says so
+    fn check_offline(&mut self, err: &eyre::Report) {
+        if ErrorKind::of(err) == ErrorKind::RobotOffline && self.offline_since.is_none() {
+            warn!("Robot {} is offline", self.id);
+            self.offline_since = Some(Utc::now());
+        }
+    }
+}
+
+impl From<&eyre::Report> for RobotError {
+    fn from(err: &eyre::Report) -> RobotError {
+        RobotError {
+            kind: ErrorKind::of(err),
+            message: err
+                .downcast_ref::<RequestError>()
+                .map_or_else(|| err.to_string(), |err| err.message.clone()),
+            time: Utc::now(),
+        }
+    }
+}
 
 #[derive(Clone, PartialEq, Deserialize, Serialize, Debug)]
 pub enum RobotCmd {
     #[serde(alias = "startCleaning", alias = "start_cleaning")]
     StartCleaning,
+    /// `StartCleaning` with other than the default `HouseCleaningParams`
+    #[serde(alias = "startCleaningWith", alias = "start_cleaning_with")]
+    StartCleaningWith(HouseCleaningParams),
     #[serde(alias = "stopCleaning", alias = "stop_cleaning")]
     StopCleaning,
     #[serde(alias = "pauseCleaning", alias = "pause_cleaning")]
@@ -78,7 +219,9 @@ pub enum RobotCmd {
 impl fmt::Display for RobotCmd {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         match self {
-            RobotCmd::StartCleaning => write!(f, "startCleaning"),
+            RobotCmd::StartCleaning | RobotCmd::StartCleaningWith(_) => {
+                write!(f, "startCleaning")
+            }
             RobotCmd::StopCleaning => write!(f, "stopCleaning"),
             RobotCmd::PauseCleaning => write!(f, "pauseCleaning"),
             RobotCmd::ResumeCleaning => write!(f, "resumeCleaning"),
@@ -88,20 +231,53 @@ impl fmt::Display for RobotCmd {
     }
 }
 
+/// Short names of the commands, accepted as plain text on the set topics and by Homie
+pub const PLAIN_COMMANDS: &[(&str, RobotCmd)] = &[
+    ("start", RobotCmd::StartCleaning),
+    ("stop", RobotCmd::StopCleaning),
+    ("pause", RobotCmd::PauseCleaning),
+    ("resume", RobotCmd::ResumeCleaning),
+    ("dock", RobotCmd::SendToBase),
+];
+
 impl RobotCmd {
-    pub fn build_robot_message(&self) -> RobotMessage {
+    /// Command from a short name like `dock` or a protocol name like `sendToBase` or
+    /// `send_to_base`, ignoring case
+    pub fn from_name(name: &str) -> Option<RobotCmd> {
+        let name = name.trim();
+        if let Some((_, cmd)) = PLAIN_COMMANDS
+            .iter()
+            .find(|(short, _)| short.eq_ignore_ascii_case(name))
+        {
+            return Some(cmd.clone());
+        }
+        let normalized = name.replace('_', "").to_lowercase();
+        [
+            RobotCmd::StartCleaning,
+            RobotCmd::StopCleaning,
+            RobotCmd::PauseCleaning,
+            RobotCmd::ResumeCleaning,
+            RobotCmd::SendToBase,
+            RobotCmd::GetRobotState,
+        ]
+        .into_iter()
+        .find(|cmd| cmd.to_string().to_lowercase() == normalized)
+    }
+
+    pub fn build_robot_message(&self, req_id: &str) -> RobotMessage {
         match self {
             RobotCmd::StartCleaning => RobotMessage {
-                req_id: String::from("77"),
+                req_id: req_id.to_string(),
+                cmd: String::from("startCleaning"),
+                params: Some(HouseCleaningParams::default()),
+            },
+            RobotCmd::StartCleaningWith(params) => RobotMessage {
+                req_id: req_id.to_string(),
                 cmd: String::from("startCleaning"),
-                params: Some(HouseCleaningParams {
-                    category: 4,
-                    mode: 1,
-                    navigation_mode: 2,
-                }),
+                params: Some(params.clone()),
             },
             other => RobotMessage {
-                req_id: String::from("77"),
+                req_id: req_id.to_string(),
                 cmd: other.to_string(),
                 params: None,
             },
@@ -109,40 +285,221 @@ impl RobotCmd {
     }
 }
 
-type SharedRobots = Arc<AsyncMutex<Vec<Robot>>>;
+/// A command to several robots that failed for some of them
+#[derive(Debug)]
+pub struct CommandFailures {
+    /// Robots that got the command
+    pub succeeded: Vec<String>,
+    /// Id and error of each robot that didn't
+    pub failed: Vec<(String, String)>,
+}
+
+impl fmt::Display for CommandFailures {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let failed: Vec<String> = self
+            .failed
+            .iter()
+            .map(|(id, err)| format!("{} ({})", id, err))
+            .collect();
+        write!(f, "Command failed for {}", failed.join(", "))
+    }
+}
+
+impl std::error::Error for CommandFailures {}
+
+static NEXT_REQ_ID: AtomicU64 = AtomicU64::new(1);
+
+/// Unique id for a robot request, echoed back as `reqId` in the response
+fn next_req_id() -> String {
+    NEXT_REQ_ID.fetch_add(1, Ordering::Relaxed).to_string()
+}
+
+/// Fails if the response carries the `reqId` of another request
+fn check_req_id(req_id: &str, response: &str) -> Result<()> {
+    let response_id = serde_json::from_str::<serde_json::Value>(response)
+        .ok()
+        .and_then(|response| match response.get("reqId")? {
+            serde_json::Value::String(id) => Some(id.clone()),
+            other => Some(other.to_string()),
+        });
+
+    match response_id {
+        Some(response_id) if response_id != req_id => Err(eyre!(
+            "Response to request {} has reqId {}",
+            req_id,
+            response_id
+        )),
+        Some(_) => Ok(()),
+        None => {
+            debug!("Response to request {} has no reqId", req_id);
+            Ok(())
+        }
+    }
+}
+
+/// Fails if the robot answered with a `result` other than `ok`, e.g. `invalid_state`
+fn check_result(response: &str) -> Result<()> {
+    let result = serde_json::from_str::<serde_json::Value>(response)
+        .ok()
+        .and_then(|response| Some(response.get("result")?.as_str()?.to_string()));
+
+    match result {
+        Some(result) if result != "ok" => {
+            Err(RequestError::new(ErrorKind::Rejected, result).into())
+        }
+        _ => Ok(()),
+    }
+}
+
+/// Longest wait before a retry, in milliseconds
+const MAX_RETRY_DELAY: u64 = 5 * 60 * 1000;
+
+/// Wait before retry `attempt + 1`: `retry_delay` doubled on every attempt plus up to
+/// `retry_delay` of jitter, at most `MAX_RETRY_DELAY`
+fn retry_delay(retry_delay: u64, attempt: u32) -> Duration {
+    let base_delay = 2u64
+        .checked_pow(attempt)
+        .map_or(u64::MAX, |factor| retry_delay.saturating_mul(factor));
+    let jitter = rand::thread_rng().gen_range(0..=retry_delay);
+    Duration::from_millis(base_delay.saturating_add(jitter).min(MAX_RETRY_DELAY))
+}
+
+/// Number of requests kept in the log of each robot
+const REQUEST_LOG_SIZE: usize = 20;
 
-#[derive(Clone)]
-pub struct Neato {
+/// A request sent to a robot, see `Neato::send`
+#[derive(Clone, Serialize, Debug)]
+pub struct RequestLogEntry {
+    pub req_id: String,
+    pub cmd: String,
+    pub sent_at: DateTime<Utc>,
+    pub duration_ms: u64,
+    pub error: Option<String>,
+}
+
+/// The discovered robots. The lock is only held to read or update them, never across a
+/// request, so a slow robot does not hold up the others.
+#[derive(Clone, Default)]
+struct Registry {
+    robots: Arc<Mutex<Vec<Robot>>>,
+}
+
+impl Registry {
+    fn all(&self) -> Vec<Robot> {
+        self.robots.lock().unwrap().clone()
+    }
+
+    fn replace(&self, robots: Vec<Robot>) {
+        *self.robots.lock().unwrap() = robots;
+    }
+
+    /// Updates the robot with the given serial, if it is still there
+    fn update<T>(&self, serial: &str, update: impl FnOnce(&mut Robot) -> T) -> Option<T> {
+        self.robots
+            .lock()
+            .unwrap()
+            .iter_mut()
+            .find(|r| r.serial == serial)
+            .map(update)
+    }
+}
+
+pub fn create_accounts(neato_settings: &NeatoSettings) -> Vec<Arc<Account>> {
+    neato_settings
+        .accounts()
+        .iter()
+        .map(|a| Arc::new(Account::new(a, neato_settings)))
+        .collect()
+}
+
+pub struct Neato<B: RobotBackend = CloudBackend> {
+    backend: Arc<B>,
     mqtt_client: MqttClient,
-    settings: NeatoSettings,
-    robots: SharedRobots,
+    settings: watch::Receiver<Settings>,
+    accounts: Arc<RwLock<Vec<Arc<Account>>>>,
+    robots: Registry,
     last_state_update: Arc<Mutex<Option<chrono::DateTime<chrono::Utc>>>>,
+    /// Recent requests per robot serial, newest last
+    request_log: Arc<Mutex<HashMap<String, VecDeque<RequestLogEntry>>>>,
+    rules: Arc<Mutex<Rules>>,
+    /// Signalled after every poll
+    state_updates: Arc<watch::Sender<()>>,
+    /// Running sequential starts, per group
+    sequences: Arc<Mutex<HashMap<String, AbortHandle>>>,
+}
+
+impl<B: RobotBackend> Clone for Neato<B> {
+    fn clone(&self) -> Self {
+        Neato {
+            backend: Arc::clone(&self.backend),
+            mqtt_client: self.mqtt_client.clone(),
+            settings: self.settings.clone(),
+            accounts: Arc::clone(&self.accounts),
+            robots: self.robots.clone(),
+            last_state_update: Arc::clone(&self.last_state_update),
+            request_log: Arc::clone(&self.request_log),
+            rules: Arc::clone(&self.rules),
+            state_updates: Arc::clone(&self.state_updates),
+            sequences: Arc::clone(&self.sequences),
+        }
+    }
 }
 
-impl Neato {
-    pub fn new(mqtt_client: MqttClient, neato_settings: &NeatoSettings) -> Neato {
+impl<B: RobotBackend> Neato<B> {
+    pub fn new(
+        backend: B,
+        mqtt_client: MqttClient,
+        settings: watch::Receiver<Settings>,
+    ) -> Neato<B> {
+        let accounts = create_accounts(&settings.borrow().neato);
         Neato {
+            backend: Arc::new(backend),
             mqtt_client,
-            settings: neato_settings.clone(),
-            robots: Arc::new(AsyncMutex::new(Vec::new())),
+            settings,
+            accounts: Arc::new(RwLock::new(accounts)),
+            robots: Registry::default(),
             last_state_update: Arc::new(Mutex::new(None)),
+            request_log: Arc::new(Mutex::new(HashMap::new())),
+            rules: Arc::new(Mutex::new(Rules::default())),
+            state_updates: Arc::new(watch::channel(()).0),
+            sequences: Arc::new(Mutex::new(HashMap::new())),
         }
     }
 
+    /// Current Neato settings, these may change when the settings file is reloaded
+    fn settings(&self) -> NeatoSettings {
+        self.settings.borrow().neato.clone()
+    }
+
+    fn accounts(&self) -> Vec<Arc<Account>> {
+        self.accounts.read().unwrap().clone()
+    }
+
     // pub async fn get_robot_names(&self) -> Vec<String> {
     //     self.robots.lock().await.iter().map(|r| r.name.clone()).collect()
     // }
 
-    pub async fn init(mut self) -> color_eyre::Result<Neato> {
+    pub async fn init(self) -> color_eyre::Result<Neato<B>> {
         info!("Initializing Neato cloud integration");
-        self.robots = Arc::new(AsyncMutex::new(get_robots(&self.settings.clone()).await?));
+        self.robots.replace(
+            discover_robots(
+                self.backend.as_ref(),
+                &self.accounts(),
+                &self.mqtt_client.settings(),
+            )
+            .await?,
+        );
         // let robots_with_states = update_robot_states(get_robots(neato_settings).await?).await?;
 
-        for robot in self.robots.lock().await.iter() {
+        for robot in self.robots.all().iter() {
             let _r = robot.clone();
-            info!("Found robot: {:?}", robot.name);
+            info!(
+                "Found robot: {:?} on account {} with id {:?}",
+                robot.name, robot.account, robot.id
+            );
             debug!("Robot info: {:?}", robot);
         }
+        self.check_groups();
 
         // Start the state polling loop
         match self.init_polling().await {
@@ -159,23 +516,84 @@ impl Neato {
             }
         };
 
+        self.init_reload();
+
         info!("Neato connection initialized");
 
         Ok(self)
     }
 
+    /// Fetches the states of all robots, a few at a time. Offline robots are only tried
+    /// every `offline_poll_interval`.
     async fn force_update_states(&self) -> color_eyre::Result<()> {
-        for robot in self.robots.lock().await.iter_mut() {
-            debug!("Robot info before update: {:?}", robot);
+        let settings = self.settings();
+        let battery_threshold = self.settings.borrow().events.battery_threshold;
+        let limit = Arc::new(Semaphore::new(settings.max_concurrent_requests));
+        let mut fetches = JoinSet::new();
+        for robot in self.robots.all() {
+            if robot.skip_offline_poll(settings.offline_poll_interval) {
+                debug!("Robot {} is offline, not polling it yet", robot.id);
+                continue;
+            }
+            let neato = self.clone();
+            let limit = Arc::clone(&limit);
+            fetches.spawn(async move {
+                let _permit = limit
+                    .acquire_owned()
+                    .await
+                    .expect("semaphore is never closed");
+                debug!("Robot info before update: {:?}", robot);
+                let state = neato
+                    .send(&robot, &RobotCmd::GetRobotState)
+                    .await
+                    .and_then(|result| Ok(serde_json::from_str::<NeatoState>(&result)?));
+                (robot, state)
+            });
+        }
+
+        while let Some(fetch) = fetches.join_next().await {
+            let (robot, state) = fetch?;
+            if let Err(err) = &state {
+                error!("Could not update the state of robot {}: {}", robot.id, err);
+            }
+            let events = self.robots.update(&robot.serial, |robot| {
+                let mut events = Vec::new();
+                match state {
+                    Ok(state) => {
+                        let previous = robot.state.take();
+                        if let Some(previous) = &previous {
+                            events.extend(
+                                events::detect(previous, &state, battery_threshold)
+                                    .into_iter()
+                                    .map(|kind| Event::new(kind, Some(previous), &state)),
+                            );
+                        }
+                        if let Some(since) = robot.offline_since.take() {
+                            info!("Robot {} is back online", robot.id);
+                            let mut event =
+                                Event::new(EventKind::BackOnline, previous.as_ref(), &state);
+                            event.offline_since = Some(since);
+                            events.push(event);
+                        }
+                        robot.state = Some(state);
+                        robot.last_error = None;
+                    }
+                    Err(err) => {
+                        robot.check_offline(&err);
+                        robot.last_error = Some(RobotError::from(&err));
+                    }
+                }
+                debug!("Robot info after update: {:?}\n", robot);
+                events
+            });
 
-            let result = send_command(robot, &RobotCmd::GetRobotState).await?;
-            let serialized_result: NeatoState = serde_json::from_str(&result).unwrap();
-            robot.state = Some(serialized_result);
-            // robot.state = robot_map.get(&robot.serial).unwrap().state.clone();
-            debug!("Robot info after update: {:?}\n", robot);
+            for event in events.unwrap_or_default() {
+                self.publish_event(&robot, &event).await;
+            }
         }
 
         *self.last_state_update.lock().unwrap() = Some(Utc::now());
+        self.state_updates.send_replace(());
 
         Ok(())
     }
@@ -187,7 +605,7 @@ impl Neato {
             Ok(last_state_update) => {
                 if last_state_update.is_some()
                     && (Utc::now() - last_state_update.unwrap()).num_seconds()
-                        < self.settings.cache_timeout as i64
+                        < self.settings().cache_timeout as i64
                 {
                     let ms_ago = (Utc::now() - last_state_update.unwrap()).num_milliseconds();
                     info!(
@@ -212,15 +630,13 @@ impl Neato {
     }
 
     async fn init_polling(&self) -> color_eyre::Result<()> {
-        let poll_rate = Duration::from_millis(self.settings.poll_interval as u64 * 1000);
         let neato = self.clone();
         let mqtt_client = self.mqtt_client.clone();
+        let mut settings = self.settings.clone();
 
         tokio::spawn(async move {
-            let mut interval = tokio::time::interval(poll_rate);
-
             loop {
-                interval.tick().await;
+                let last_poll = Instant::now();
                 match neato.update_states().await {
                     Ok(_) => (),
                     Err(err) => {
@@ -228,76 +644,563 @@ impl Neato {
                     }
                 };
 
-                for robot in neato.robots.lock().await.iter() {
+                for robot in neato.robots.all().iter() {
                     robot.publish(mqtt_client.clone()).await.unwrap();
                 }
+                neato.check_rules().await;
+
+                // Wait for the poll interval, which may change while waiting
+                loop {
+                    let poll_rate = Duration::from_secs(neato.settings().poll_interval as u64);
+                    tokio::select! {
+                        _ = tokio::time::sleep_until(last_poll + poll_rate) => break,
+                        changed = settings.changed() => {
+                            if changed.is_err() {
+                                tokio::time::sleep_until(last_poll + poll_rate).await;
+                                break;
+                            }
+                        }
+                    }
+                }
             }
         });
         Ok(())
     }
 
-    async fn init_react_to_subscription_messages(&self) -> color_eyre::Result<()> {
-        let mut s = self.clone();
+    /// Applies reloaded settings that need more than reading the current value: new
+    /// credentials, topic ids or backend mean logging in and discovering the robots again.
+    fn init_reload(&self) {
+        let neato = self.clone();
+        let mut settings = self.settings.clone();
+        let mut current = settings.borrow().clone();
 
         tokio::spawn(async move {
-            loop {
-                s.mqtt_client
-                    .rx
-                    .changed()
-                    .await
-                    .expect("Expected rx channel never to close");
-                let msg = s.mqtt_client.rx.borrow().clone();
+            while settings.changed().await.is_ok() {
+                let new = settings.borrow_and_update().clone();
 
-                println!("Received update instruction! Device: {:?}", msg);
+                if new.neato.poll_interval != current.neato.poll_interval {
+                    info!("Poll interval changed to {}s", new.neato.poll_interval);
+                }
+                if new.neato.cache_timeout != current.neato.cache_timeout {
+                    info!("Cache timeout changed to {}s", new.neato.cache_timeout);
+                }
+                if new.neato.dry_run != current.neato.dry_run {
+                    info!("Setting neato.dry_run changed to {}", new.neato.dry_run);
+                }
+                let backend_changed = neato.backend.reload(&new);
 
-                match msg {
-                    Some(SendAction {
-                        action: RobotCmd::GetRobotState,
-                        ..
-                    }) => {
-                        debug!("We don't do state updates from set messages");
-                    }
-                    Some(SendAction { action, id }) => {
-                        let robots = match id.as_str() {
-                            // if id is "set", send to all robots
-                            "set" => s.robots.lock().await.clone(),
-                            _ => {
-                                // Filter out all robots that don't match the id
-                                s.robots
-                                    .lock()
-                                    .await
-                                    .clone()
-                                    .into_iter()
-                                    .filter(|r| r.name == id)
-                                    .collect()
-                            }
-                        };
+                let accounts_changed = new.neato.accounts() != current.neato.accounts()
+                    || new.neato.request_interval != current.neato.request_interval
+                    || new.neato.request_timeout != current.neato.request_timeout;
+                let ids_changed = new.mqtt.topic_id != current.mqtt.topic_id
+                    || new.mqtt.aliases != current.mqtt.aliases;
+                if new.groups != current.groups {
+                    info!("Robot groups changed");
+                }
+                current = new;
 
-                        info!("Sending command: {}", action);
-                        if robots.is_empty() {
-                            error!(
-                                "No robots found with name \"{}\". Aborting sending command",
-                                id
-                            );
-                            continue;
+                if accounts_changed || ids_changed || backend_changed {
+                    info!("Neato accounts, topic ids or backend changed, discovering robots again");
+                    let accounts = create_accounts(&current.neato);
+                    match discover_robots(neato.backend.as_ref(), &accounts, &current.mqtt).await {
+                        Ok(robots) => {
+                            *neato.accounts.write().unwrap() = accounts;
+                            neato.robots.replace(robots);
+                            neato.check_groups();
+                            // Fetch states for the new robots on the next poll
+                            *neato.last_state_update.lock().unwrap() = None;
+                        }
+                        Err(err) => {
+                            error!("Could not discover robots with the new settings: {}", err);
                         }
-                        info!(
-                            "Affected robots: {:?}",
-                            robots
-                                .iter()
-                                .map(|r| r.name.clone())
-                                .collect::<Vec<String>>()
-                        );
-                        if s.settings.dry_run {
-                            info!("Setting neato.dry_run enabled, not sending command");
-                        } else {
-                            for robot in robots {
-                                send_command(&robot, &action).await.unwrap();
+                    }
+                }
+            }
+        });
+    }
+
+    /// Sends a command to the robot. State requests that fail with a transport error or
+    /// because of rate limiting are retried, movement commands are sent only once.
+    async fn send(&self, robot: &Robot, cmd: &RobotCmd) -> Result<String> {
+        let settings = self.settings();
+        let retries = if *cmd == RobotCmd::GetRobotState {
+            settings.retries
+        } else {
+            0
+        };
+
+        let mut attempt = 0;
+        loop {
+            let result = self.send_once(robot, cmd).await;
+            let kind = match &result {
+                Err(err) if attempt < retries && ErrorKind::of(err).is_retryable() => {
+                    ErrorKind::of(err)
+                }
+                _ => return result,
+            };
+
+            let delay = retry_delay(settings.retry_delay, attempt);
+            attempt += 1;
+            warn!(
+                "Robot {} {} failed ({}), retry {} of {} in {:.1}s",
+                robot.id,
+                cmd,
+                kind,
+                attempt,
+                retries,
+                delay.as_secs_f64()
+            );
+            tokio::time::sleep(delay).await;
+        }
+    }
+
+    /// Sends a single request with `send_request` and records it in the request log of the
+    /// robot
+    async fn send_once(&self, robot: &Robot, cmd: &RobotCmd) -> Result<String> {
+        let account = self
+            .accounts()
+            .into_iter()
+            .find(|a| a.name() == robot.account);
+        let (entry, result) =
+            send_request(self.backend.as_ref(), account.as_deref(), robot, cmd).await;
+        self.log_request(robot, entry).await;
+
+        result
+    }
+
+    async fn log_request(&self, robot: &Robot, entry: RequestLogEntry) {
+        let entries = {
+            let mut request_log = self.request_log.lock().unwrap();
+            let entries = request_log.entry(robot.serial.clone()).or_default();
+            entries.push_back(entry);
+            if entries.len() > REQUEST_LOG_SIZE {
+                entries.pop_front();
+            }
+            entries.clone()
+        };
+
+        let mqtt_settings = self.mqtt_client.settings();
+        if !mqtt_settings.publish_request_log {
+            return;
+        }
+        let topic = format!(
+            "{}/requests",
+            robot_topic(&mqtt_settings.topic, robot, &mqtt_settings)
+        );
+        let payload = match serde_json::to_string(&entries) {
+            Ok(payload) => payload,
+            Err(err) => {
+                error!("Could not serialize the request log: {}", err);
+                return;
+            }
+        };
+        if let Err(err) = self
+            .mqtt_client
+            .client()
+            .publish(topic, mqtt_settings.qos.state.into(), false, payload)
+            .await
+        {
+            error!("Could not publish the request log: {}", err);
+        }
+    }
+
+    /// Publishes a notification for every rule that matches the latest robot states
+    async fn check_rules(&self) {
+        let settings = self.settings.borrow().clone();
+        if settings.rules.is_empty() {
+            return;
+        }
+
+        let now = Utc::now();
+        let notifications = {
+            let mut rules = self.rules.lock().unwrap();
+            self.robots
+                .all()
+                .iter()
+                .flat_map(|robot| {
+                    let topic = robot_topic(&settings.notifications.topic, robot, &settings.mqtt);
+                    rules
+                        .evaluate(
+                            &settings.rules,
+                            &settings.notifications,
+                            &robot.to_public(),
+                            now,
+                        )
+                        .into_iter()
+                        .map(move |notification| (topic.clone(), notification))
+                })
+                .collect::<Vec<_>>()
+        };
+
+        for (topic, notification) in notifications {
+            info!("Robot {}: {}", notification.robot, notification.message);
+            let payload = match serde_json::to_string(&notification) {
+                Ok(payload) => payload,
+                Err(err) => {
+                    error!("Could not serialize notification: {}", err);
+                    continue;
+                }
+            };
+            if let Err(err) = self
+                .mqtt_client
+                .client()
+                .publish(
+                    topic,
+                    settings.mqtt.qos.events.into(),
+                    settings.mqtt.retain.events,
+                    payload,
+                )
+                .await
+            {
+                error!("Could not publish notification: {}", err);
+            }
+        }
+    }
+
+    /// Publishes a one-shot event to `{topic}/events`
+    async fn publish_event(&self, robot: &Robot, event: &Event) {
+        debug!("Robot {} event {:?}", robot.id, event.event);
+        let mqtt_settings = self.mqtt_client.settings();
+        let topic = format!(
+            "{}/events",
+            robot_topic(&mqtt_settings.topic, robot, &mqtt_settings)
+        );
+        let payload = match serde_json::to_string(event) {
+            Ok(payload) => payload,
+            Err(err) => {
+                error!("Could not serialize event: {}", err);
+                return;
+            }
+        };
+        let properties = Properties {
+            user_properties: robot.user_properties(),
+            ..Properties::default()
+        };
+        if let Err(err) = self
+            .mqtt_client
+            .client()
+            .publish_with_properties(
+                topic,
+                mqtt_settings.qos.events.into(),
+                mqtt_settings.retain.events,
+                payload,
+                properties,
+            )
+            .await
+        {
+            error!("Could not publish event for robot {}: {}", robot.id, err);
+        }
+    }
+
+    /// Recent requests sent to the robot, oldest first
+    pub fn request_log(&self, robot: &Robot) -> Vec<RequestLogEntry> {
+        self.request_log
+            .lock()
+            .unwrap()
+            .get(&robot.serial)
+            .map(|entries| entries.iter().cloned().collect())
+            .unwrap_or_default()
+    }
+
+    /// Persistent maps of the robot with the given id
+    pub async fn maps(&self, id: &str) -> Result<serde_json::Value> {
+        let robot = self
+            .robots()
+            .into_iter()
+            .find(|r| r.id == id)
+            .ok_or_else(|| eyre!("No robot found with id \"{}\"", id))?;
+        let account = self
+            .accounts()
+            .into_iter()
+            .find(|a| a.name() == robot.account)
+            .ok_or_else(|| eyre!("No account found for robot \"{}\"", id))?;
+
+        account
+            .timeout(self.backend.fetch_maps(&account, &robot))
+            .await
+    }
+
+    pub fn robots(&self) -> Vec<Robot> {
+        self.robots.all()
+    }
+
+    pub fn dry_run(&self) -> bool {
+        self.settings().dry_run
+    }
+
+    /// Warns about groups that can't be addressed or have members that don't exist
+    fn check_groups(&self) {
+        let robots = self.robots.all();
+        for (name, group) in self.settings.borrow().groups.iter() {
+            if robots.iter().any(|r| &r.id == name) {
+                warn!(
+                    "Group {} has the same id as a robot, `{}/set` only controls the robot",
+                    name, name
+                );
+            }
+            for member in group.robots() {
+                if !robots.iter().any(|r| &r.id == member) {
+                    warn!("Group {} contains unknown robot \"{}\"", name, member);
+                }
+            }
+        }
+    }
+
+    /// Whether commands to `id` reach any robot: `set`, a robot id or a group name
+    pub fn is_target(&self, id: &str) -> bool {
+        id == "set"
+            || self.robots.all().iter().any(|r| r.id == id)
+            || self.settings.borrow().groups.contains_key(id)
+    }
+
+    /// Fails unless every robot addressed by `id` has the values the topic of a command gave
+    /// to the other placeholders, e.g. `{model}` in `neato/{model}/{id}/set`
+    fn check_topic_values(&self, id: &str, values: &TopicValues) -> Result<()> {
+        let robots = self.robots.all();
+        let group = self.settings.borrow().groups.get(id).cloned();
+        let addressed = match (id, group) {
+            ("set", _) => robots,
+            _ if robots.iter().any(|r| r.id == id) => {
+                robots.into_iter().filter(|r| r.id == id).collect()
+            }
+            (_, Some(group)) => self.group_robots(&group),
+            (_, None) => Vec::new(),
+        };
+
+        let mqtt_settings = self.mqtt_client.settings();
+        let mismatched: Vec<String> = addressed
+            .iter()
+            .filter(|robot| {
+                let robot_values = robot_values(robot, &mqtt_settings);
+                values.iter().any(|(placeholder, value)| {
+                    *placeholder != Placeholder::Id && robot_values.get(placeholder) != Some(value)
+                })
+            })
+            .map(|robot| robot.id.clone())
+            .collect();
+        if !mismatched.is_empty() {
+            return Err(eyre!(
+                "The topic does not match robot {}, not sending the command",
+                mismatched.join(", ")
+            ));
+        }
+
+        Ok(())
+    }
+
+    /// Robots of the group, in the order they are listed
+    fn group_robots(&self, group: &GroupSettings) -> Vec<Robot> {
+        let robots = self.robots.all();
+        group
+            .robots()
+            .iter()
+            .filter_map(|id| robots.iter().find(|r| &r.id == id).cloned())
+            .collect()
+    }
+
+    /// Stops the sequential start of the group, or of all groups if `group` is `None`
+    fn cancel_sequences(&self, group: Option<&str>) {
+        let mut sequences = self.sequences.lock().unwrap();
+        sequences.retain(|name, task| {
+            if group.is_some_and(|group| group != name) {
+                return true;
+            }
+            if !task.is_finished() {
+                info!("Group {}: cancelling the sequential start", name);
+                task.abort();
+            }
+            false
+        });
+    }
+
+    fn record_error(&self, robot: &Robot, err: &eyre::Report) {
+        self.robots.update(&robot.serial, |r| {
+            r.check_offline(err);
+            r.last_error = Some(RobotError::from(err));
+        });
+    }
+
+    /// Starts the robots one after another, each once the previous one is back on its base.
+    /// A robot that has not left its base within `start_timeout` is given up on, so that a
+    /// refused start does not hold up the rest.
+    fn start_sequence(
+        &self,
+        group: String,
+        robots: Vec<Robot>,
+        action: RobotCmd,
+        start_timeout: Duration,
+    ) {
+        let neato = self.clone();
+        let mut updates = self.state_updates.subscribe();
+        let name = group.clone();
+
+        let task = tokio::spawn(async move {
+            for robot in robots {
+                info!("Group {}: starting {}", group, robot.id);
+                if let Err(err) = neato.send(&robot, &action).await {
+                    error!("Group {}: could not start {}: {}", group, robot.id, err);
+                    neato.record_error(&robot, &err);
+                    continue;
+                }
+                // Only states fetched after the command count
+                updates.borrow_and_update();
+                let deadline = Instant::now() + start_timeout;
+                let mut left = false;
+                loop {
+                    let changed = if left {
+                        updates.changed().await
+                    } else {
+                        match tokio::time::timeout_at(deadline, updates.changed()).await {
+                            Ok(changed) => changed,
+                            Err(_) => {
+                                warn!(
+                                    "Group {}: {} did not leave its base within {}s, moving on",
+                                    group,
+                                    robot.id,
+                                    start_timeout.as_secs()
+                                );
+                                break;
                             }
                         }
+                    };
+                    if changed.is_err() {
+                        return;
                     }
-                    _ => {}
+                    let Some(current) = neato
+                        .robots
+                        .all()
+                        .into_iter()
+                        .find(|r| r.serial == robot.serial)
+                    else {
+                        warn!("Group {}: {} is gone, moving on", group, robot.id);
+                        break;
+                    };
+                    if current.offline_since.is_some() {
+                        warn!("Group {}: {} is offline, moving on", group, robot.id);
+                        break;
+                    }
+                    let Some(state) = &current.state else {
+                        continue;
+                    };
+                    if is_done(state, left) {
+                        debug!("Group {}: {} is done", group, robot.id);
+                        break;
+                    }
+                    left |= has_left(state);
+                }
+            }
+            info!("Group {}: all robots started", group);
+        });
+
+        self.sequences
+            .lock()
+            .unwrap()
+            .insert(name, task.abort_handle());
+    }
+
+    /// Sends the action to the robot named `id`, to the robots of the group `id`, or to
+    /// all robots if `id` is "set". Returns the names of the affected robots.
+    pub async fn handle_action(&self, send_action: SendAction) -> color_eyre::Result<Vec<String>> {
+        let SendAction { action, id } = send_action;
+        if action == RobotCmd::GetRobotState {
+            debug!("We don't do state updates from set messages");
+            return Ok(Vec::new());
+        }
+
+        let group = self.settings.borrow().groups.get(&id).cloned();
+        let mut start_timeout = None;
+        let robots: Vec<Robot> = match id.as_str() {
+            // if id is "set", send to all robots
+            "set" => {
+                self.cancel_sequences(None);
+                self.robots.all()
+            }
+            _ if self.robots.all().iter().any(|r| r.id == id) => {
+                // Filter out all robots that don't match the id
+                self.robots
+                    .all()
+                    .into_iter()
+                    .filter(|r| r.id == id)
+                    .collect()
+            }
+            _ => match &group {
+                Some(group) => {
+                    // Any command to the group replaces a sequential start in progress
+                    self.cancel_sequences(Some(&id));
+                    if group.sequential() {
+                        start_timeout = Some(group.start_timeout());
+                    }
+                    self.group_robots(group)
+                }
+                None => Vec::new(),
+            },
+        };
+
+        info!("Sending command: {}", action);
+        if robots.is_empty() {
+            return Err(eyre!(
+                "No robots found with id \"{}\". Aborting sending command",
+                id
+            ));
+        }
+        let names = robots.iter().map(|r| r.id.clone()).collect::<Vec<String>>();
+        info!("Affected robots: {:?}", names);
+        let starts_cleaning = matches!(
+            action,
+            RobotCmd::StartCleaning | RobotCmd::StartCleaningWith(_)
+        );
+        if self.dry_run() {
+            info!("Setting neato.dry_run enabled, not sending command");
+        } else if let Some(start_timeout) = start_timeout.filter(|_| starts_cleaning) {
+            info!("Group {}: starting one robot at a time", id);
+            self.start_sequence(id, robots, action, start_timeout);
+        } else {
+            // One failing robot must not keep the others from getting the command
+            let mut succeeded = Vec::new();
+            let mut failed = Vec::new();
+            for robot in robots {
+                match self.send(&robot, &action).await {
+                    Ok(_) => succeeded.push(robot.id.clone()),
+                    Err(err) => {
+                        self.record_error(&robot, &err);
+                        if names.len() == 1 {
+                            return Err(err);
+                        }
+                        failed.push((robot.id.clone(), err.to_string()));
+                    }
+                }
+            }
+            if !failed.is_empty() {
+                return Err(CommandFailures { succeeded, failed }.into());
+            }
+        }
+
+        Ok(names)
+    }
+
+    async fn init_react_to_subscription_messages(&self) -> color_eyre::Result<()> {
+        let s = self.clone();
+
+        tokio::spawn(async move {
+            let mut rx = s.mqtt_client.rx.lock().await;
+            while let Some(msg) = rx.recv().await {
+                println!("Received update instruction! Device: {:?}", msg);
+
+                let MqttCommand {
+                    action,
+                    values,
+                    response_to,
+                } = msg;
+                let result = match s.check_topic_values(&action.id, &values) {
+                    Ok(()) => s.handle_action(action).await,
+                    Err(err) => Err(err),
                 };
+                if let Err(err) = &result {
+                    error!("Error handling command: {}", err);
+                }
+                if let Some(response_to) = response_to {
+                    s.mqtt_client.respond(&response_to, &result).await;
+                }
             }
         });
 
@@ -305,70 +1208,760 @@ impl Neato {
     }
 }
 
-async fn get_robots(config: &NeatoSettings) -> Result<Vec<Robot>> {
-    let body = AuthBody {
-        email: config.email.clone(),
-        password: config.password.clone(),
-    };
+/// A Neato account with its own session and request rate limit
+pub struct Account {
+    pub settings: NeatoAccount,
+    /// Access token of the current session, if logged in
+    pub session: AsyncMutex<Option<Secret<String>>>,
+    request_interval: Duration,
+    request_timeout: Duration,
+    next_request: AsyncMutex<Instant>,
+}
 
-    let token = surf::post(&format!("{}/sessions", BASE_URL))
-        .body(surf::Body::from_json(&body).map_err(|err| eyre!(err))?)
-        .await
-        .map_err(|err| eyre!(err))?
-        .body_json::<SessionsResponse>()
-        .await
-        .map_err(|err| eyre!(err))?
-        .access_token;
+impl Account {
+    pub fn new(account_settings: &NeatoAccount, neato_settings: &NeatoSettings) -> Account {
+        Account {
+            settings: account_settings.clone(),
+            session: AsyncMutex::new(None),
+            request_interval: Duration::from_millis(
+                account_settings
+                    .request_interval
+                    .unwrap_or(neato_settings.request_interval),
+            ),
+            request_timeout: Duration::from_secs(neato_settings.request_timeout),
+            next_request: AsyncMutex::new(Instant::now()),
+        }
+    }
 
-    let robots = surf::get(&format!("{}/users/me/robots", BASE_URL))
-        .header("Authorization", format!("Bearer {}", token))
-        .send()
-        .await
-        .map_err(|err| eyre!(err))?
-        // .body_string() // in case you want to debug the whole response
-        .body_json::<Vec<Robot>>()
-        .await
-        .map_err(|err| eyre!(err))?;
+    pub fn name(&self) -> &str {
+        self.settings.name()
+    }
 
-    Ok(robots)
+    /// Waits until the next request to this account is allowed
+    pub async fn throttle(&self) {
+        let mut next_request = self.next_request.lock().await;
+        tokio::time::sleep_until(*next_request).await;
+        *next_request = Instant::now() + self.request_interval;
+    }
+
+    /// Fails with a transport error if the request takes longer than `request_timeout`
+    pub async fn timeout<T>(&self, request: impl Future<Output = Result<T>>) -> Result<T> {
+        match tokio::time::timeout(self.request_timeout, request).await {
+            Ok(result) => result,
+            Err(_) => Err(RequestError::new(
+                ErrorKind::Transport,
+                format!("no response within {}s", self.request_timeout.as_secs()),
+            )
+            .into()),
+        }
+    }
 }
 
-async fn send_command(robot: &Robot, cmd: &RobotCmd) -> Result<String> {
-    // https://developers.neatorobotics.com/api/nucleo
-    let robot_message = cmd.build_robot_message();
+/// Sends a single request, respecting the rate limit and timeout of the robot's account, and
+/// checks the response. Returns the entry for the request log along with the result.
+pub async fn send_request<B: RobotBackend>(
+    backend: &B,
+    account: Option<&Account>,
+    robot: &Robot,
+    cmd: &RobotCmd,
+) -> (RequestLogEntry, Result<String>) {
+    if let Some(account) = account {
+        account.throttle().await;
+    }
+
+    let req_id = next_req_id();
+    let sent_at = Utc::now();
+    let start = Instant::now();
+    let request = backend.send_command(robot, cmd, &req_id);
+    let response = match account {
+        Some(account) => account.timeout(request).await,
+        None => request.await,
+    };
+    let result = response.and_then(|response| {
+        check_req_id(&req_id, &response)?;
+        check_result(&response)?;
+        Ok(response)
+    });
 
+    let entry = RequestLogEntry {
+        req_id,
+        cmd: cmd.to_string(),
+        sent_at,
+        duration_ms: start.elapsed().as_millis() as u64,
+        error: result.as_ref().err().map(|err| err.to_string()),
+    };
     debug!(
-        "Robot name {}, Sending command: {:?}",
-        robot.name, robot_message
+        "Robot {} request {} ({}) took {}ms{}",
+        robot.id,
+        entry.req_id,
+        entry.cmd,
+        entry.duration_ms,
+        entry
+            .error
+            .as_ref()
+            .map(|err| format!(", failed: {}", err))
+            .unwrap_or_default()
     );
 
-    let body = serde_json::to_string(&robot_message)?;
-    let serial = robot.serial.to_lowercase();
-    let date: String = format!("{}", Utc::now().format("%a, %d %b %Y %H:%M:%S GMT"));
-    let string_to_sign = format!("{}\n{}\n{}", serial, date, body);
-
-    // Create HMAC-SHA256 instance which implements `Mac` trait
-    let mut mac = HmacSha256::new_from_slice(robot.secret_key.as_bytes())
-        .expect("HMAC can take key of any size");
-    mac.update(string_to_sign.as_bytes());
-
-    let signature = hex::encode(mac.finalize().into_bytes());
-
-    let result = surf::post(&format!(
-        "{}/vendors/neato/robots/{}/messages",
-        robot.nucleo_url, robot.serial
-    ))
-    .header("Accept", "application/vnd.neato.nucleo.v1")
-    .header("Date", date)
-    .header("Authorization", format!("NEATOAPP {}", signature))
-    .body(surf::Body::from_json(&robot_message).map_err(|err| eyre!(err))?)
-    .await
-    .map_err(|err| eyre!(err))?
-    .body_string()
-    .await
-    .map_err(|err| eyre!(err))?;
-
-    debug!("response: {}", result);
-
-    Ok(result)
+    (entry, result)
+}
+
+/// Fetches the robots of all accounts, failing if two robots would end up with the same `{id}`
+pub async fn discover_robots<B: RobotBackend>(
+    backend: &B,
+    accounts: &[Arc<Account>],
+    mqtt_settings: &MqttSettings,
+) -> Result<Vec<Robot>> {
+    if accounts.is_empty() {
+        return Err(eyre!("No Neato account configured"));
+    }
+
+    let mut robots: Vec<Robot> = Vec::new();
+    for account in accounts {
+        for mut robot in account.timeout(backend.discover_robots(account)).await? {
+            robot.account = account.name().to_string();
+            robot.id = robot_topic_id(&robot, &account.settings.topic_prefix, mqtt_settings);
+            robots.push(robot);
+        }
+    }
+
+    let mut seen: HashMap<&str, &Robot> = HashMap::new();
+    let mut collisions = Vec::new();
+    for robot in robots.iter() {
+        if robot.id == "set" {
+            collisions.push(format!(
+                "\"{}\" (account {}) uses id \"set\", which is reserved for commands to all robots",
+                robot.name, robot.account
+            ));
+        }
+        if let Some(other) = seen.insert(robot.id.as_str(), robot) {
+            collisions.push(format!(
+                "\"{}\" (account {}) and \"{}\" (account {}) both use id \"{}\"",
+                other.name, other.account, robot.name, robot.account, robot.id
+            ));
+        }
+    }
+    if !collisions.is_empty() {
+        return Err(eyre!(
+            "Robot id collision, set an alias or a `topic_prefix` on one of the accounts: {}",
+            collisions.join(", ")
+        ));
+    }
+
+    Ok(robots)
+}
+
+pub async fn get_robots<B: RobotBackend>(backend: &B, settings: &Settings) -> Result<Vec<Robot>> {
+    discover_robots(backend, &create_accounts(&settings.neato), &settings.mqtt).await
+}
+
+#[cfg(test)]
+pub mod tests {
+    use super::*;
+    use crate::{
+        backend::fake::{self, FakeBackend},
+        settings::GroupTable,
+    };
+
+    /// `Neato` with the robots of `backend` discovered and no MQTT connection
+    pub async fn neato(backend: FakeBackend, settings: Settings) -> Neato<FakeBackend> {
+        let (_tx, settings) = watch::channel(settings);
+        let mqtt_client = MqttClient::disconnected(settings.clone());
+        let neato = Neato::new(backend, mqtt_client, settings);
+        let robots = discover_robots(
+            neato.backend.as_ref(),
+            &neato.accounts(),
+            &neato.mqtt_client.settings(),
+        )
+        .await
+        .unwrap();
+        neato.robots.replace(robots);
+        neato
+    }
+
+    fn action(id: &str, action: RobotCmd) -> SendAction {
+        SendAction {
+            id: id.to_string(),
+            action,
+        }
+    }
+
+    #[tokio::test]
+    async fn colliding_ids_are_rejected() {
+        let backend = FakeBackend::new(&["Living Room", "living room"]);
+        let mut settings = fake::settings();
+        let accounts = create_accounts(&settings.neato);
+        assert!(discover_robots(&backend, &accounts, &settings.mqtt)
+            .await
+            .is_ok());
+
+        settings.mqtt.topic_id = crate::topics::TopicId::Slug;
+        let err = discover_robots(&backend, &accounts, &settings.mqtt)
+            .await
+            .unwrap_err();
+        assert!(err.to_string().contains("both use id \"living-room\""));
+
+        // An alias resolves the collision
+        settings
+            .mqtt
+            .aliases
+            .insert(String::from("living room"), String::from("upstairs"));
+        let robots = discover_robots(&backend, &accounts, &settings.mqtt)
+            .await
+            .unwrap();
+        assert_eq!(robots[1].id, "upstairs");
+    }
+
+    #[tokio::test]
+    async fn set_is_reserved() {
+        let backend = FakeBackend::new(&["set"]);
+        let settings = fake::settings();
+
+        let err = discover_robots(&backend, &create_accounts(&settings.neato), &settings.mqtt)
+            .await
+            .unwrap_err();
+
+        assert!(err.to_string().contains("reserved"));
+    }
+
+    #[tokio::test]
+    async fn set_sends_to_all_robots() {
+        let backend = FakeBackend::new(&["Vacuum", "Botvac2"]);
+        let commands = Arc::clone(&backend.commands);
+        let neato = neato(backend, fake::settings()).await;
+
+        let affected = neato
+            .handle_action(action("set", RobotCmd::StartCleaning))
+            .await
+            .unwrap();
+
+        assert_eq!(affected, vec!["Vacuum", "Botvac2"]);
+        assert_eq!(
+            *commands.lock().unwrap(),
+            vec![
+                (fake::serial("Vacuum"), RobotCmd::StartCleaning),
+                (fake::serial("Botvac2"), RobotCmd::StartCleaning),
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn set_sends_to_all_robots_after_a_failure() {
+        let backend = FakeBackend::new(&["Vacuum", "Botvac2"]);
+        let commands = Arc::clone(&backend.commands);
+        let failures = Arc::clone(&backend.failures);
+        let neato = neato(backend, fake::settings()).await;
+        failures.lock().unwrap().push_back(ErrorKind::RobotOffline);
+
+        let err = neato
+            .handle_action(action("set", RobotCmd::SendToBase))
+            .await
+            .unwrap_err();
+
+        assert_eq!(commands.lock().unwrap().len(), 2);
+        let failures = err.downcast_ref::<CommandFailures>().unwrap();
+        assert_eq!(failures.succeeded, vec!["Botvac2"]);
+        assert_eq!(failures.failed.len(), 1);
+        assert_eq!(failures.failed[0].0, "Vacuum");
+    }
+
+    #[tokio::test]
+    async fn named_id_sends_to_that_robot_only() {
+        let backend = FakeBackend::new(&["Vacuum", "Botvac2"]);
+        let commands = Arc::clone(&backend.commands);
+        let neato = neato(backend, fake::settings()).await;
+
+        let affected = neato
+            .handle_action(action("Botvac2", RobotCmd::SendToBase))
+            .await
+            .unwrap();
+
+        assert_eq!(affected, vec!["Botvac2"]);
+        assert_eq!(
+            *commands.lock().unwrap(),
+            vec![(fake::serial("Botvac2"), RobotCmd::SendToBase)]
+        );
+    }
+
+    #[tokio::test]
+    async fn unknown_id_is_an_error() {
+        let backend = FakeBackend::new(&["Vacuum"]);
+        let commands = Arc::clone(&backend.commands);
+        let neato = neato(backend, fake::settings()).await;
+
+        assert!(neato
+            .handle_action(action("Nope", RobotCmd::StartCleaning))
+            .await
+            .is_err());
+        assert!(commands.lock().unwrap().is_empty());
+    }
+
+    /// Commands other than state requests, as (robot serial, command)
+    fn sent(commands: &Mutex<Vec<(String, RobotCmd)>>) -> Vec<(String, RobotCmd)> {
+        commands
+            .lock()
+            .unwrap()
+            .iter()
+            .filter(|(_, cmd)| *cmd != RobotCmd::GetRobotState)
+            .cloned()
+            .collect()
+    }
+
+    #[tokio::test]
+    async fn group_sends_to_its_robots_in_order() {
+        let backend = FakeBackend::new(&["Vacuum", "Botvac2", "Kitchen"]);
+        let commands = Arc::clone(&backend.commands);
+        let mut settings = fake::settings();
+        settings.groups.insert(
+            String::from("upstairs"),
+            GroupSettings::Robots(vec![String::from("Botvac2"), String::from("Vacuum")]),
+        );
+        let neato = neato(backend, settings).await;
+        assert!(neato.is_target("upstairs"));
+        assert!(!neato.is_target("downstairs"));
+
+        let affected = neato
+            .handle_action(action("upstairs", RobotCmd::SendToBase))
+            .await
+            .unwrap();
+
+        assert_eq!(affected, vec!["Botvac2", "Vacuum"]);
+        assert_eq!(
+            sent(&commands),
+            vec![
+                (fake::serial("Botvac2"), RobotCmd::SendToBase),
+                (fake::serial("Vacuum"), RobotCmd::SendToBase),
+            ]
+        );
+    }
+
+    /// `upstairs` starts Vacuum, then Botvac2, states are fetched on every poll
+    fn sequential_settings() -> Settings {
+        let mut settings = fake::settings();
+        settings.neato.cache_timeout = 0;
+        settings.groups.insert(
+            String::from("upstairs"),
+            GroupSettings::Table(GroupTable {
+                robots: vec![String::from("Vacuum"), String::from("Botvac2")],
+                sequential: true,
+                start_timeout: None,
+            }),
+        );
+        settings
+    }
+
+    #[tokio::test]
+    async fn sequential_group_waits_for_the_base() {
+        let backend = FakeBackend::new(&["Vacuum", "Botvac2"]);
+        let commands = Arc::clone(&backend.commands);
+        let backend_states = Arc::clone(&backend.states);
+        let neato = neato(backend, sequential_settings()).await;
+
+        neato
+            .handle_action(action("upstairs", RobotCmd::StartCleaning))
+            .await
+            .unwrap();
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        assert_eq!(
+            sent(&commands),
+            vec![(fake::serial("Vacuum"), RobotCmd::StartCleaning)]
+        );
+
+        // Cleaning, then back on the base
+        backend_states.lock().unwrap().insert(
+            fake::serial("Vacuum"),
+            VecDeque::from([(RobotState::Busy, false), (RobotState::Idle, true)]),
+        );
+        neato.update_states().await.unwrap();
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        assert_eq!(sent(&commands).len(), 1);
+
+        neato.update_states().await.unwrap();
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        assert_eq!(
+            sent(&commands),
+            vec![
+                (fake::serial("Vacuum"), RobotCmd::StartCleaning),
+                (fake::serial("Botvac2"), RobotCmd::StartCleaning),
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn sequential_group_waits_until_the_robot_left_the_base() {
+        let backend = FakeBackend::new(&["Vacuum", "Botvac2"]);
+        let commands = Arc::clone(&backend.commands);
+        let backend_states = Arc::clone(&backend.states);
+        let neato = neato(backend, sequential_settings()).await;
+
+        neato
+            .handle_action(action("upstairs", RobotCmd::StartCleaning))
+            .await
+            .unwrap();
+        // Still docked and idle on the first poll after the start
+        backend_states.lock().unwrap().insert(
+            fake::serial("Vacuum"),
+            VecDeque::from([(RobotState::Idle, true), (RobotState::Busy, false)]),
+        );
+        for _ in 0..2 {
+            neato.update_states().await.unwrap();
+            tokio::time::sleep(Duration::from_millis(50)).await;
+        }
+
+        assert_eq!(
+            sent(&commands),
+            vec![(fake::serial("Vacuum"), RobotCmd::StartCleaning)]
+        );
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn sequential_group_moves_on_when_the_robot_stays_docked() {
+        let backend = FakeBackend::new(&["Vacuum", "Botvac2"]);
+        let commands = Arc::clone(&backend.commands);
+        let neato = neato(backend, sequential_settings()).await;
+
+        neato
+            .handle_action(action("upstairs", RobotCmd::StartCleaning))
+            .await
+            .unwrap();
+        // Docked and idle on every poll
+        for _ in 0..5 {
+            tokio::time::sleep(Duration::from_secs(60)).await;
+            neato.update_states().await.unwrap();
+        }
+        assert_eq!(
+            sent(&commands),
+            vec![(fake::serial("Vacuum"), RobotCmd::StartCleaning)]
+        );
+
+        tokio::time::sleep(Duration::from_secs(6 * 60)).await;
+
+        assert_eq!(
+            sent(&commands),
+            vec![
+                (fake::serial("Vacuum"), RobotCmd::StartCleaning),
+                (fake::serial("Botvac2"), RobotCmd::StartCleaning),
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn sequential_group_moves_on_when_the_start_is_rejected() {
+        let backend = FakeBackend::new(&["Vacuum", "Botvac2"]);
+        let commands = Arc::clone(&backend.commands);
+        backend
+            .results
+            .lock()
+            .unwrap()
+            .push_back("not_on_charge_base");
+        let neato = neato(backend, sequential_settings()).await;
+
+        neato
+            .handle_action(action("upstairs", RobotCmd::StartCleaning))
+            .await
+            .unwrap();
+        tokio::time::sleep(Duration::from_millis(50)).await;
+
+        assert_eq!(
+            sent(&commands),
+            vec![
+                (fake::serial("Vacuum"), RobotCmd::StartCleaning),
+                (fake::serial("Botvac2"), RobotCmd::StartCleaning),
+            ]
+        );
+        let vacuum = &neato.robots()[0];
+        assert_eq!(
+            vacuum.last_error.as_ref().unwrap().kind,
+            ErrorKind::Rejected
+        );
+    }
+
+    #[tokio::test]
+    async fn other_placeholders_must_match_the_robot() {
+        let mut settings = fake::settings();
+        settings.mqtt.set_topic = String::from("neato/{model}/{id}/set");
+        settings.groups.insert(
+            String::from("upstairs"),
+            GroupSettings::Robots(vec![String::from("Vacuum")]),
+        );
+        let neato = neato(FakeBackend::new(&["Vacuum"]), settings).await;
+        let values = |model: &str, id: &str| {
+            TopicValues::from([
+                (Placeholder::Model, model.to_string()),
+                (Placeholder::Id, id.to_string()),
+            ])
+        };
+
+        assert!(neato
+            .check_topic_values("Vacuum", &values("BotVacD7Connected", "Vacuum"))
+            .is_ok());
+        assert!(neato
+            .check_topic_values("Vacuum", &values("WrongModel", "Vacuum"))
+            .is_err());
+        assert!(neato
+            .check_topic_values("upstairs", &values("WrongModel", "upstairs"))
+            .is_err());
+        assert!(neato
+            .check_topic_values("set", &values("WrongModel", "set"))
+            .is_err());
+    }
+
+    #[tokio::test]
+    async fn dry_run_sends_nothing() {
+        let backend = FakeBackend::new(&["Vacuum"]);
+        let commands = Arc::clone(&backend.commands);
+        let mut settings = fake::settings();
+        settings.neato.dry_run = true;
+        let neato = neato(backend, settings).await;
+
+        let affected = neato
+            .handle_action(action("Vacuum", RobotCmd::StartCleaning))
+            .await
+            .unwrap();
+
+        assert_eq!(affected, vec!["Vacuum"]);
+        assert!(commands.lock().unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn state_requests_are_not_forwarded() {
+        let backend = FakeBackend::new(&["Vacuum"]);
+        let commands = Arc::clone(&backend.commands);
+        let neato = neato(backend, fake::settings()).await;
+
+        let affected = neato
+            .handle_action(action("Vacuum", RobotCmd::GetRobotState))
+            .await
+            .unwrap();
+
+        assert!(affected.is_empty());
+        assert!(commands.lock().unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn states_are_cached_until_cache_timeout() {
+        let backend = FakeBackend::new(&["Vacuum"]);
+        let commands = Arc::clone(&backend.commands);
+        let neato = neato(backend, fake::settings()).await;
+
+        neato.update_states().await.unwrap();
+        neato.update_states().await.unwrap();
+
+        assert_eq!(commands.lock().unwrap().len(), 1);
+        assert!(neato.robots()[0].state.is_some());
+    }
+
+    #[tokio::test]
+    async fn states_are_fetched_again_after_cache_timeout() {
+        let backend = FakeBackend::new(&["Vacuum"]);
+        let commands = Arc::clone(&backend.commands);
+        let mut settings = fake::settings();
+        settings.neato.cache_timeout = 0;
+        let neato = neato(backend, settings).await;
+
+        neato.update_states().await.unwrap();
+        neato.update_states().await.unwrap();
+
+        assert_eq!(commands.lock().unwrap().len(), 2);
+    }
+
+    #[tokio::test]
+    async fn requests_get_unique_ids_and_are_logged() {
+        let backend = FakeBackend::new(&["Vacuum"]);
+        let neato = neato(backend, fake::settings()).await;
+
+        neato
+            .handle_action(action("Vacuum", RobotCmd::StartCleaning))
+            .await
+            .unwrap();
+        neato
+            .handle_action(action("Vacuum", RobotCmd::PauseCleaning))
+            .await
+            .unwrap();
+
+        let log = neato.request_log(&neato.robots()[0]);
+        assert_eq!(log.len(), 2);
+        assert_ne!(log[0].req_id, log[1].req_id);
+        assert_eq!(log[0].cmd, "startCleaning");
+        assert_eq!(log[1].cmd, "pauseCleaning");
+        assert!(log.iter().all(|entry| entry.error.is_none()));
+    }
+
+    #[tokio::test]
+    async fn state_requests_are_retried_on_transport_errors() {
+        let backend = FakeBackend::new(&["Vacuum"]);
+        let commands = Arc::clone(&backend.commands);
+        backend
+            .failures
+            .lock()
+            .unwrap()
+            .extend([ErrorKind::Transport, ErrorKind::RateLimited]);
+        let neato = neato(backend, fake::settings()).await;
+
+        neato.update_states().await.unwrap();
+
+        let robot = &neato.robots()[0];
+        assert_eq!(commands.lock().unwrap().len(), 3);
+        assert!(robot.state.is_some());
+        assert!(robot.last_error.is_none());
+    }
+
+    #[tokio::test]
+    async fn auth_errors_are_not_retried() {
+        let backend = FakeBackend::new(&["Vacuum"]);
+        let commands = Arc::clone(&backend.commands);
+        backend.failures.lock().unwrap().push_back(ErrorKind::Auth);
+        let neato = neato(backend, fake::settings()).await;
+
+        neato.update_states().await.unwrap();
+
+        let robot = &neato.robots()[0];
+        assert_eq!(commands.lock().unwrap().len(), 1);
+        assert_eq!(robot.last_error.as_ref().unwrap().kind, ErrorKind::Auth);
+    }
+
+    #[tokio::test]
+    async fn movement_commands_are_not_retried() {
+        let backend = FakeBackend::new(&["Vacuum"]);
+        let commands = Arc::clone(&backend.commands);
+        backend
+            .failures
+            .lock()
+            .unwrap()
+            .push_back(ErrorKind::Transport);
+        let neato = neato(backend, fake::settings()).await;
+
+        assert!(neato
+            .handle_action(action("Vacuum", RobotCmd::StartCleaning))
+            .await
+            .is_err());
+
+        let robot = &neato.robots()[0];
+        assert_eq!(commands.lock().unwrap().len(), 1);
+        assert_eq!(
+            robot.last_error.as_ref().unwrap().kind,
+            ErrorKind::Transport
+        );
+    }
+
+    #[tokio::test]
+    async fn rejected_commands_are_errors() {
+        let backend = FakeBackend::new(&["Vacuum"]);
+        backend
+            .results
+            .lock()
+            .unwrap()
+            .push_back("not_on_charge_base");
+        let neato = neato(backend, fake::settings()).await;
+
+        let err = neato
+            .handle_action(action("Vacuum", RobotCmd::StartCleaning))
+            .await
+            .unwrap_err();
+
+        assert_eq!(ErrorKind::of(&err), ErrorKind::Rejected);
+        assert!(err.to_string().contains("not_on_charge_base"));
+        let robot = &neato.robots()[0];
+        assert_eq!(robot.last_error.as_ref().unwrap().kind, ErrorKind::Rejected);
+    }
+
+    #[tokio::test]
+    async fn commands_are_not_blocked_by_a_slow_state_fetch() {
+        let backend = FakeBackend::new(&["Vacuum", "Botvac2"]);
+        backend
+            .delays
+            .lock()
+            .unwrap()
+            .insert(fake::serial("Botvac2"), Duration::from_secs(5));
+        let neato = neato(backend, fake::settings()).await;
+
+        let updating = neato.clone();
+        tokio::spawn(async move { updating.update_states().await });
+        tokio::time::sleep(Duration::from_millis(50)).await;
+
+        let sent = tokio::time::timeout(
+            Duration::from_secs(1),
+            neato.handle_action(action("Vacuum", RobotCmd::StartCleaning)),
+        )
+        .await;
+
+        assert_eq!(sent.unwrap().unwrap(), vec!["Vacuum"]);
+        // The fast robot's state is stored without waiting for the slow one
+        assert!(neato.robots()[0].state.is_some());
+        assert!(neato.robots()[1].state.is_none());
+    }
+
+    #[tokio::test]
+    async fn offline_robots_are_polled_less_often() {
+        let backend = FakeBackend::new(&["Vacuum"]);
+        let commands = Arc::clone(&backend.commands);
+        backend
+            .failures
+            .lock()
+            .unwrap()
+            .push_back(ErrorKind::RobotOffline);
+        let mut settings = fake::settings();
+        settings.neato.cache_timeout = 0;
+        let neato = neato(backend, settings).await;
+
+        neato.update_states().await.unwrap();
+        let robot = neato.robots()[0].to_public();
+        assert!(!robot.online);
+        assert_eq!(robot.last_error.unwrap().kind, ErrorKind::RobotOffline);
+
+        neato.update_states().await.unwrap();
+        assert_eq!(commands.lock().unwrap().len(), 1);
+    }
+
+    #[tokio::test]
+    async fn offline_robots_come_back_online() {
+        let backend = FakeBackend::new(&["Vacuum"]);
+        backend
+            .failures
+            .lock()
+            .unwrap()
+            .push_back(ErrorKind::RobotOffline);
+        let mut settings = fake::settings();
+        settings.neato.cache_timeout = 0;
+        settings.neato.offline_poll_interval = 0;
+        let neato = neato(backend, settings).await;
+
+        neato.update_states().await.unwrap();
+        assert!(neato.robots()[0].offline_since.is_some());
+
+        neato.update_states().await.unwrap();
+        let robot = neato.robots()[0].to_public();
+        assert!(robot.online);
+        assert!(robot.state.is_some());
+        assert!(robot.last_error.is_none());
+    }
+
+    #[test]
+    fn response_with_other_req_id_is_an_error() {
+        assert!(check_req_id("12", r#"{"reqId":"12","result":"ok"}"#).is_ok());
+        assert!(check_req_id("12", r#"{"reqId":"13","result":"ok"}"#).is_err());
+        assert!(check_req_id("12", r#"{"result":"ok"}"#).is_ok());
+    }
+
+    #[test]
+    fn retry_delay_is_capped() {
+        assert!(retry_delay(500, 0) >= Duration::from_millis(500));
+        assert!(retry_delay(500, 2) >= Duration::from_millis(2000));
+        assert_eq!(
+            retry_delay(500, 100),
+            Duration::from_millis(MAX_RETRY_DELAY)
+        );
+        assert_eq!(
+            retry_delay(u64::MAX, 1),
+            Duration::from_millis(MAX_RETRY_DELAY)
+        );
+    }
+
+    #[test]
+    fn result_other_than_ok_is_an_error() {
+        assert!(check_result(r#"{"reqId":"12","result":"ok"}"#).is_ok());
+        assert!(check_result(r#"{"reqId":"12","state":1}"#).is_ok());
+        let err = check_result(r#"{"reqId":"12","result":"invalid_state"}"#).unwrap_err();
+        assert_eq!(ErrorKind::of(&err), ErrorKind::Rejected);
+    }
 }
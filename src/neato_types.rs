@@ -1,30 +1,81 @@
 use std::fmt;
 
+use chrono::{DateTime, Utc};
 use serde::{Deserialize, Serialize};
 use serde_repr::{Deserialize_repr, Serialize_repr};
 
-#[derive(Clone, Deserialize, Serialize, Debug)]
+use crate::{backend::ErrorKind, secret::Secret};
+
+#[derive(Clone, Deserialize, Debug)]
 pub struct Robot {
+    /// Used as `{id}` in topics, set on discovery
+    #[serde(default)]
+    pub id: String,
+    /// Name of the Neato account the robot belongs to, set on discovery
+    #[serde(default)]
+    pub account: String,
     pub mac_address: String,
     pub model: String,
     pub name: String,
     pub nucleo_url: String,
-    pub secret_key: String,
+    pub secret_key: Secret<String>,
     pub serial: String,
+    /// Firmware version as reported by Beehive
+    #[serde(default)]
+    pub firmware: Option<String>,
     pub state: Option<NeatoState>,
+    /// Error of the last request, cleared by the next successful state update
+    #[serde(skip)]
+    pub last_error: Option<RobotError>,
+    /// When the cloud first reported the robot as not online, `None` while it is online
+    #[serde(skip)]
+    pub offline_since: Option<DateTime<Utc>>,
+}
+
+/// A failed request to a robot
+#[derive(Clone, Deserialize, Serialize, Debug)]
+pub struct RobotError {
+    pub kind: ErrorKind,
+    pub message: String,
+    pub time: DateTime<Utc>,
 }
 
+/// Robot info without the secret key, safe to hand out over MQTT and HTTP
 #[derive(Clone, Deserialize, Serialize, Debug)]
 pub struct PublicRobot {
+    pub id: String,
+    pub account: String,
     pub mac_address: String,
     pub model: String,
     pub name: String,
     pub nucleo_url: String,
     pub serial: String,
+    pub firmware: Option<String>,
     pub state: Option<NeatoState>,
+    pub last_error: Option<RobotError>,
+    pub online: bool,
 }
 
-#[derive(Serialize, Debug)]
+impl From<&Robot> for PublicRobot {
+    fn from(robot: &Robot) -> PublicRobot {
+        PublicRobot {
+            id: robot.id.clone(),
+            account: robot.account.clone(),
+            mac_address: robot.mac_address.clone(),
+            model: robot.model.clone(),
+            name: robot.name.clone(),
+            nucleo_url: robot.nucleo_url.clone(),
+            serial: robot.serial.clone(),
+            firmware: robot.firmware.clone(),
+            state: robot.state.clone(),
+            last_error: robot.last_error.clone(),
+            online: robot.offline_since.is_none(),
+        }
+    }
+}
+
+#[derive(Clone, Deserialize, Serialize, Debug, PartialEq)]
+#[serde(default)]
 pub struct HouseCleaningParams {
     /// Should be set to 4 for persistent map
     /// 2 without persistent map
@@ -34,10 +85,21 @@ pub struct HouseCleaningParams {
     pub mode: u32,
 
     /// 1 is normal, 2 is extra care, 3 is deep. 3 requires mode = 2.
-    #[serde(rename = "navigationMode")]
+    #[serde(rename = "navigationMode", alias = "navigation_mode")]
     pub navigation_mode: u32,
 }
 
+/// Used by a plain `StartCleaning`: persistent map, eco, extra care
+impl Default for HouseCleaningParams {
+    fn default() -> HouseCleaningParams {
+        HouseCleaningParams {
+            category: 4,
+            mode: 1,
+            navigation_mode: 2,
+        }
+    }
+}
+
 #[derive(Serialize, Debug)]
 pub struct RobotMessage {
     #[serde(rename = "reqId")]
@@ -0,0 +1,50 @@
+use std::{fs, path::PathBuf, time::Duration};
+
+use log::{debug, error, info};
+use tokio::sync::watch;
+
+use crate::{
+    settings::{read_settings, Settings},
+    validation::settings_file,
+};
+
+/// How often the settings file is checked for changes
+const WATCH_INTERVAL: Duration = Duration::from_secs(2);
+
+fn modified(file: &PathBuf) -> Option<std::time::SystemTime> {
+    fs::metadata(file).and_then(|m| m.modified()).ok()
+}
+
+/// Watches the settings file and sends the settings to `tx` whenever the file changes.
+/// Settings that fail validation are logged and not applied.
+pub fn watch(path: Option<String>, tx: watch::Sender<Settings>) {
+    let Some(file) = settings_file(path.as_deref().unwrap_or("Settings")) else {
+        debug!("No settings file to watch for changes");
+        return;
+    };
+
+    tokio::spawn(async move {
+        let mut last_modified = modified(&file);
+        loop {
+            tokio::time::sleep(WATCH_INTERVAL).await;
+
+            let current_modified = modified(&file);
+            if current_modified == last_modified {
+                continue;
+            }
+            last_modified = current_modified;
+
+            match read_settings(path.as_deref()) {
+                Ok(settings) => {
+                    if settings != *tx.borrow() {
+                        info!("Settings file {} changed, applying", file.display());
+                        tx.send_replace(settings);
+                    }
+                }
+                Err(err) => {
+                    error!("Not applying changed settings: {}", err);
+                }
+            }
+        }
+    });
+}
@@ -0,0 +1,522 @@
+use std::{collections::HashMap, fmt, str::FromStr, time::Duration};
+
+use chrono::{DateTime, Utc};
+use serde::Serialize;
+
+use crate::{
+    neato_types::{NeatoState, PublicRobot},
+    settings::{NotificationSettings, RuleSettings},
+};
+
+/// Robot properties a rule can test
+const VARIABLES: &[&str] = &[
+    "online",
+    "charge",
+    "is_docked",
+    "is_charging",
+    "is_schedule_enabled",
+    "dock_has_been_seen",
+    "state",
+    "action",
+    "alert",
+    "error",
+];
+
+/// Values of `state` that may be used on their own, e.g. `Busy for > 2h`
+const STATES: &[&str] = &["Invalid", "Idle", "Busy", "Paused", "Error"];
+
+#[derive(Debug, PartialEq)]
+pub struct ParseError(String);
+
+impl fmt::Display for ParseError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
+impl std::error::Error for ParseError {}
+
+#[derive(Clone, Debug, PartialEq)]
+enum Token {
+    Ident(String),
+    Number(f64),
+    Text(String),
+    Op(&'static str),
+}
+
+fn tokenize(input: &str) -> Result<Vec<Token>, ParseError> {
+    const OPS: &[&str] = &["&&", "||", "==", "!=", "<=", ">=", "<", ">", "!", "(", ")"];
+
+    let mut tokens = Vec::new();
+    let mut rest = input.trim_start();
+    while let Some(c) = rest.chars().next() {
+        if let Some(op) = OPS.iter().find(|op| rest.starts_with(**op)) {
+            tokens.push(Token::Op(op));
+            rest = &rest[op.len()..];
+        } else if c.is_ascii_digit() {
+            let end = rest
+                .find(|c: char| !c.is_ascii_digit() && c != '.')
+                .unwrap_or(rest.len());
+            let number = rest[..end]
+                .parse()
+                .map_err(|_| ParseError(format!("invalid number `{}`", &rest[..end])))?;
+            tokens.push(Token::Number(number));
+            rest = &rest[end..];
+        } else if c.is_ascii_alphabetic() || c == '_' {
+            let end = rest
+                .find(|c: char| !c.is_ascii_alphanumeric() && c != '_')
+                .unwrap_or(rest.len());
+            tokens.push(Token::Ident(rest[..end].to_string()));
+            rest = &rest[end..];
+        } else if c == '"' {
+            let end = rest[1..]
+                .find('"')
+                .ok_or_else(|| ParseError(String::from("unterminated string")))?;
+            tokens.push(Token::Text(rest[1..end + 1].to_string()));
+            rest = &rest[end + 2..];
+        } else {
+            return Err(ParseError(format!("unexpected `{}`", c)));
+        }
+        rest = rest.trim_start();
+    }
+
+    Ok(tokens)
+}
+
+#[derive(Clone, Debug, PartialEq)]
+enum Value {
+    Bool(bool),
+    Number(f64),
+    Text(String),
+    None,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+enum Expr {
+    Not(Box<Expr>),
+    And(Box<Expr>, Box<Expr>),
+    Or(Box<Expr>, Box<Expr>),
+    /// A variable on its own, true if it is `true` or set
+    Is(String),
+    Compare(String, &'static str, Value),
+}
+
+struct Parser {
+    tokens: Vec<Token>,
+    position: usize,
+}
+
+impl Parser {
+    fn peek(&self) -> Option<&Token> {
+        self.tokens.get(self.position)
+    }
+
+    fn next(&mut self) -> Option<Token> {
+        let token = self.tokens.get(self.position).cloned();
+        self.position += 1;
+        token
+    }
+
+    fn eat(&mut self, op: &'static str) -> bool {
+        if self.peek() == Some(&Token::Op(op)) {
+            self.position += 1;
+            true
+        } else {
+            false
+        }
+    }
+
+    fn or(&mut self) -> Result<Expr, ParseError> {
+        let mut expr = self.and()?;
+        while self.eat("||") {
+            expr = Expr::Or(Box::new(expr), Box::new(self.and()?));
+        }
+        Ok(expr)
+    }
+
+    fn and(&mut self) -> Result<Expr, ParseError> {
+        let mut expr = self.unary()?;
+        while self.eat("&&") {
+            expr = Expr::And(Box::new(expr), Box::new(self.unary()?));
+        }
+        Ok(expr)
+    }
+
+    fn unary(&mut self) -> Result<Expr, ParseError> {
+        if self.eat("!") {
+            return Ok(Expr::Not(Box::new(self.unary()?)));
+        }
+        if self.eat("(") {
+            let expr = self.or()?;
+            if !self.eat(")") {
+                return Err(ParseError(String::from("missing `)`")));
+            }
+            return Ok(expr);
+        }
+
+        let name = match self.next() {
+            Some(Token::Ident(name)) => name,
+            Some(token) => return Err(ParseError(format!("unexpected {:?}", token))),
+            None => return Err(ParseError(String::from("unexpected end of rule"))),
+        };
+        if STATES.contains(&name.as_str()) {
+            return Ok(Expr::Compare(
+                String::from("state"),
+                "==",
+                Value::Text(name),
+            ));
+        }
+        if !VARIABLES.contains(&name.as_str()) {
+            return Err(ParseError(format!(
+                "unknown variable `{}`, expected one of {}",
+                name,
+                VARIABLES.join(", ")
+            )));
+        }
+
+        let op = match self.peek() {
+            Some(Token::Op(op)) if ["==", "!=", "<", "<=", ">", ">="].contains(op) => *op,
+            _ => return Ok(Expr::Is(name)),
+        };
+        self.position += 1;
+        let value = match self.next() {
+            Some(Token::Number(number)) => Value::Number(number),
+            Some(Token::Text(text)) => Value::Text(text),
+            Some(Token::Ident(ident)) => match ident.as_str() {
+                "true" => Value::Bool(true),
+                "false" => Value::Bool(false),
+                "none" | "null" => Value::None,
+                _ => Value::Text(ident),
+            },
+            _ => return Err(ParseError(format!("missing value after `{} {}`", name, op))),
+        };
+
+        Ok(Expr::Compare(name, op, value))
+    }
+
+    /// Optional `for > 10 min` at the end of a rule
+    fn duration(&mut self) -> Result<Duration, ParseError> {
+        if self.peek() != Some(&Token::Ident(String::from("for"))) {
+            return Ok(Duration::ZERO);
+        }
+        self.position += 1;
+        if !self.eat(">") && !self.eat(">=") {
+            return Err(ParseError(String::from("expected `>` after `for`")));
+        }
+        let amount = match self.next() {
+            Some(Token::Number(amount)) => amount,
+            _ => return Err(ParseError(String::from("expected a number after `for >`"))),
+        };
+        let unit = match self.peek() {
+            Some(Token::Ident(unit)) => {
+                let seconds = match unit.as_str() {
+                    "s" | "sec" | "secs" | "second" | "seconds" => 1.0,
+                    "m" | "min" | "mins" | "minute" | "minutes" => 60.0,
+                    "h" | "hr" | "hour" | "hours" => 60.0 * 60.0,
+                    "d" | "day" | "days" => 24.0 * 60.0 * 60.0,
+                    _ => return Err(ParseError(format!("unknown time unit `{}`", unit))),
+                };
+                self.position += 1;
+                seconds
+            }
+            _ => 1.0,
+        };
+
+        Duration::try_from_secs_f64(amount * unit)
+            .map_err(|_| ParseError(format!("duration `{}` is out of range", amount)))
+    }
+}
+
+/// A parsed rule condition, e.g. `charge < 15 && !is_docked` or `state == Error for > 10 min`
+#[derive(Clone, Debug, PartialEq)]
+pub struct Condition {
+    expr: Expr,
+    /// How long the condition has to hold before it matches
+    pub duration: Duration,
+}
+
+impl FromStr for Condition {
+    type Err = ParseError;
+
+    fn from_str(input: &str) -> Result<Condition, ParseError> {
+        let mut parser = Parser {
+            tokens: tokenize(input)?,
+            position: 0,
+        };
+        let expr = parser.or()?;
+        let duration = parser.duration()?;
+        if let Some(token) = parser.peek() {
+            return Err(ParseError(format!("unexpected {:?}", token)));
+        }
+
+        Ok(Condition { expr, duration })
+    }
+}
+
+fn variable(name: &str, robot: &PublicRobot) -> Value {
+    if name == "online" {
+        return Value::Bool(robot.online);
+    }
+    let Some(NeatoState {
+        alert,
+        error,
+        details,
+        state,
+        action,
+    }) = &robot.state
+    else {
+        return Value::None;
+    };
+    let text = |value: &Option<String>| value.clone().map_or(Value::None, Value::Text);
+
+    match name {
+        "charge" => Value::Number(details.charge as f64),
+        "is_docked" => Value::Bool(details.is_docked),
+        "is_charging" => Value::Bool(details.is_charging),
+        "is_schedule_enabled" => Value::Bool(details.is_schedule_enabled),
+        "dock_has_been_seen" => Value::Bool(details.dock_has_been_seen),
+        "state" => Value::Text(state.to_string()),
+        "action" => Value::Text(action.to_string()),
+        "alert" => text(alert),
+        "error" => text(error),
+        _ => Value::None,
+    }
+}
+
+impl Expr {
+    fn eval(&self, robot: &PublicRobot) -> bool {
+        match self {
+            Expr::Not(expr) => !expr.eval(robot),
+            Expr::And(left, right) => left.eval(robot) && right.eval(robot),
+            Expr::Or(left, right) => left.eval(robot) || right.eval(robot),
+            Expr::Is(name) => match variable(name, robot) {
+                Value::Bool(value) => value,
+                Value::None => false,
+                _ => true,
+            },
+            Expr::Compare(name, op, expected) => {
+                let actual = variable(name, robot);
+                match (&actual, expected) {
+                    (Value::Number(a), Value::Number(b)) => match *op {
+                        "==" => a == b,
+                        "!=" => a != b,
+                        "<" => a < b,
+                        "<=" => a <= b,
+                        ">" => a > b,
+                        ">=" => a >= b,
+                        _ => false,
+                    },
+                    (Value::Text(a), Value::Text(b)) => match *op {
+                        "==" => a.eq_ignore_ascii_case(b),
+                        "!=" => !a.eq_ignore_ascii_case(b),
+                        _ => false,
+                    },
+                    _ => match *op {
+                        "==" => actual == *expected,
+                        "!=" => actual != *expected,
+                        _ => false,
+                    },
+                }
+            }
+        }
+    }
+}
+
+/// Published to `notifications.topic` when a rule matches
+#[derive(Clone, Serialize, Debug)]
+pub struct Notification {
+    pub rule: String,
+    pub robot: String,
+    pub message: String,
+    /// Since when the condition holds
+    pub since: DateTime<Utc>,
+    pub time: DateTime<Utc>,
+    pub state: Option<NeatoState>,
+}
+
+#[derive(Default)]
+struct RuleState {
+    since: Option<DateTime<Utc>>,
+    last_notified: Option<DateTime<Utc>>,
+}
+
+/// Remembers per robot and rule since when a condition holds and when it last notified
+#[derive(Default)]
+pub struct Rules {
+    states: HashMap<(String, String), RuleState>,
+}
+
+impl Rules {
+    /// Checks all rules against the robot's latest state, returning the notifications due
+    pub fn evaluate(
+        &mut self,
+        rules: &[RuleSettings],
+        notification_settings: &NotificationSettings,
+        robot: &PublicRobot,
+        now: DateTime<Utc>,
+    ) -> Vec<Notification> {
+        let mut notifications = Vec::new();
+        for rule in rules {
+            let Ok(condition) = rule.when.parse::<Condition>() else {
+                continue;
+            };
+            let state = self
+                .states
+                .entry((robot.serial.clone(), rule.name.clone()))
+                .or_default();
+            if !condition.expr.eval(robot) {
+                state.since = None;
+                continue;
+            }
+
+            let since = *state.since.get_or_insert(now);
+            let held = (now - since).to_std().unwrap_or_default();
+            let cooldown =
+                Duration::from_secs(rule.cooldown.unwrap_or(notification_settings.cooldown));
+            let cooled_down = state.last_notified.map_or(true, |last| {
+                (now - last).to_std().unwrap_or_default() >= cooldown
+            });
+            if held < condition.duration || !cooled_down {
+                continue;
+            }
+
+            state.last_notified = Some(now);
+            let message = rule
+                .message
+                .as_deref()
+                .unwrap_or("Rule \"{rule}\" matched for {name}")
+                .replace("{rule}", &rule.name)
+                .replace("{name}", &robot.name)
+                .replace("{id}", &robot.id);
+            notifications.push(Notification {
+                rule: rule.name.clone(),
+                robot: robot.id.clone(),
+                message,
+                since,
+                time: now,
+                state: robot.state.clone(),
+            });
+        }
+
+        notifications
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::Duration as ChronoDuration;
+
+    use super::*;
+    use crate::{backend::fake, neato_types::RobotState};
+
+    fn robot(state: RobotState, is_docked: bool, charge: i8) -> PublicRobot {
+        PublicRobot {
+            state: Some(fake::state(state, is_docked, charge)),
+            ..fake::robot("Vacuum").to_public()
+        }
+    }
+
+    fn rule(name: &str, when: &str) -> RuleSettings {
+        RuleSettings {
+            name: name.to_string(),
+            when: when.to_string(),
+            message: None,
+            cooldown: None,
+        }
+    }
+
+    fn notifications() -> NotificationSettings {
+        NotificationSettings {
+            topic: String::from("home/devices/neato/{id}/notifications"),
+            cooldown: 3600,
+        }
+    }
+
+    fn matches(when: &str, robot: &PublicRobot) -> bool {
+        when.parse::<Condition>().unwrap().expr.eval(robot)
+    }
+
+    #[test]
+    fn parses_durations() {
+        let minutes = "state == Error for > 10 min".parse::<Condition>().unwrap();
+        let hours = "Busy for > 2h".parse::<Condition>().unwrap();
+
+        assert_eq!(minutes.duration, Duration::from_secs(600));
+        assert_eq!(hours.duration, Duration::from_secs(7200));
+        assert_eq!(
+            minutes.expr,
+            "state == Error".parse::<Condition>().unwrap().expr
+        );
+    }
+
+    #[test]
+    fn rejects_invalid_rules() {
+        assert!("charge <".parse::<Condition>().is_err());
+        assert!("battery < 15".parse::<Condition>().is_err());
+        assert!("Busy for > 2 weeks".parse::<Condition>().is_err());
+        assert!("(charge < 15".parse::<Condition>().is_err());
+    }
+
+    #[test]
+    fn rejects_durations_out_of_range() {
+        assert!("Busy for > 999999999999999999999 days"
+            .parse::<Condition>()
+            .is_err());
+    }
+
+    #[test]
+    fn evaluates_conditions() {
+        let stranded = robot(RobotState::Idle, false, 10);
+        let docked = robot(RobotState::Idle, true, 10);
+
+        assert!(matches("charge < 15 && !is_docked", &stranded));
+        assert!(!matches("charge < 15 && !is_docked", &docked));
+        assert!(matches("Idle || state == Busy", &docked));
+        assert!(matches("!(state == busy) && alert == none", &docked));
+        assert!(!matches("alert", &docked));
+    }
+
+    #[test]
+    fn notifies_once_the_condition_held_long_enough() {
+        let rules = vec![rule("stuck", "state == Error for > 10 min")];
+        let robot = robot(RobotState::Error, false, 50);
+        let start = Utc::now();
+        let mut state = Rules::default();
+
+        assert!(state
+            .evaluate(&rules, &notifications(), &robot, start)
+            .is_empty());
+        let later = start + ChronoDuration::minutes(11);
+        let notified = state.evaluate(&rules, &notifications(), &robot, later);
+
+        assert_eq!(notified.len(), 1);
+        assert_eq!(notified[0].message, "Rule \"stuck\" matched for Vacuum");
+        assert_eq!(notified[0].since, start);
+    }
+
+    #[test]
+    fn waits_for_the_cooldown_before_notifying_again() {
+        let rules = vec![rule("low", "charge < 15")];
+        let robot = robot(RobotState::Idle, false, 10);
+        let start = Utc::now();
+        let mut state = Rules::default();
+
+        assert_eq!(
+            state
+                .evaluate(&rules, &notifications(), &robot, start)
+                .len(),
+            1
+        );
+        let soon = start + ChronoDuration::minutes(5);
+        assert!(state
+            .evaluate(&rules, &notifications(), &robot, soon)
+            .is_empty());
+        let later = start + ChronoDuration::hours(2);
+        assert_eq!(
+            state
+                .evaluate(&rules, &notifications(), &robot, later)
+                .len(),
+            1
+        );
+    }
+}
@@ -0,0 +1,366 @@
+use std::{collections::HashMap, time::Duration};
+
+use chrono::{DateTime, Local};
+use croner::Cron;
+use log::{error, info, warn};
+use serde::Serialize;
+use tokio::sync::watch;
+
+use crate::{
+    backend::RobotBackend,
+    mqtt::{MqttClient, SendAction},
+    neato::{Neato, RobotCmd},
+    neato_types::Robot,
+    settings::{ScheduleSettings, Settings},
+    topics::{bridge_values, fill_topic, robot_values, Placeholder},
+};
+
+/// Longest sleep between checks, so that clock changes are noticed
+const MAX_SLEEP: Duration = Duration::from_secs(60);
+
+/// Retained on `{topic}/schedules/{name}`
+#[derive(Serialize, Debug)]
+struct NextRun<'a> {
+    name: &'a str,
+    cron: &'a str,
+    next_run: Option<DateTime<Local>>,
+    /// Whether the next run is a held back run tried again
+    deferred: bool,
+}
+
+struct Pending {
+    schedule: ScheduleSettings,
+    next_run: Option<DateTime<Local>>,
+    deferred: bool,
+}
+
+fn next_occurrence(
+    schedule: &ScheduleSettings,
+    after: &DateTime<Local>,
+) -> Option<DateTime<Local>> {
+    let cron = Cron::new(&schedule.cron).parse().ok()?;
+    cron.find_next_occurrence(after, false).ok()
+}
+
+/// Why the run has to be held back, if it does
+fn blocked(
+    schedule: &ScheduleSettings,
+    condition: Option<&str>,
+    robot: Option<&Robot>,
+) -> Option<String> {
+    if let Some(topic) = &schedule.condition_topic {
+        let value = schedule.condition_value.as_deref().unwrap_or("true");
+        if condition == Some(value) {
+            return Some(format!("{} is {}", topic, value));
+        }
+    }
+    if let Some(min_charge) = schedule.min_charge {
+        let charge = robot
+            .and_then(|r| r.state.as_ref())
+            .map(|s| s.details.charge);
+        if let Some(charge) = charge.filter(|charge| *charge < min_charge) {
+            return Some(format!("charge is {}%, below {}%", charge, min_charge));
+        }
+    }
+    None
+}
+
+/// Runs bridge-side cleaning schedules from the `[[schedules]]` settings
+pub struct Scheduler<B: RobotBackend> {
+    neato: Neato<B>,
+    mqtt_client: MqttClient,
+    settings: watch::Receiver<Settings>,
+    pending: HashMap<String, Pending>,
+}
+
+impl<B: RobotBackend> Scheduler<B> {
+    pub fn new(
+        neato: Neato<B>,
+        mqtt_client: MqttClient,
+        settings: watch::Receiver<Settings>,
+    ) -> Scheduler<B> {
+        Scheduler {
+            neato,
+            mqtt_client,
+            settings,
+            pending: HashMap::new(),
+        }
+    }
+
+    pub fn init(mut self) {
+        tokio::spawn(async move {
+            loop {
+                self.sync_schedules().await;
+                self.run_due().await;
+
+                let now = Local::now();
+                let sleep = self
+                    .pending
+                    .values()
+                    .filter_map(|p| p.next_run)
+                    .min()
+                    .and_then(|next| (next - now).to_std().ok())
+                    .unwrap_or(MAX_SLEEP)
+                    .min(MAX_SLEEP);
+                tokio::select! {
+                    _ = tokio::time::sleep(sleep) => {}
+                    changed = self.settings.changed() => {
+                        if changed.is_err() {
+                            tokio::time::sleep(sleep).await;
+                        }
+                    }
+                }
+            }
+        });
+    }
+
+    /// Picks up added, changed and removed schedules
+    async fn sync_schedules(&mut self) {
+        let schedules = self.settings.borrow().schedules.clone();
+        let now = Local::now();
+
+        let removed: Vec<Pending> = self
+            .pending
+            .keys()
+            .filter(|name| !schedules.iter().any(|s| &s.name == *name))
+            .cloned()
+            .collect::<Vec<_>>()
+            .into_iter()
+            .filter_map(|name| self.pending.remove(&name))
+            .collect();
+        for pending in removed {
+            info!("Schedule {} removed", pending.schedule.name);
+            self.clear(&pending.schedule).await;
+        }
+
+        for schedule in schedules {
+            if self
+                .pending
+                .get(&schedule.name)
+                .is_some_and(|p| p.schedule == schedule)
+            {
+                continue;
+            }
+            let next_run = next_occurrence(&schedule, &now);
+            info!(
+                "Schedule {} starts {} next at {}",
+                schedule.name,
+                schedule.robot,
+                next_run.map_or_else(|| String::from("never"), |t| t.to_rfc2822())
+            );
+            let pending = Pending {
+                schedule,
+                next_run,
+                deferred: false,
+            };
+            self.publish(&pending).await;
+            self.pending.insert(pending.schedule.name.clone(), pending);
+        }
+    }
+
+    async fn run_due(&mut self) {
+        let now = Local::now();
+        let due: Vec<String> = self
+            .pending
+            .values()
+            .filter(|p| p.next_run.is_some_and(|next| next <= now))
+            .map(|p| p.schedule.name.clone())
+            .collect();
+
+        for name in due {
+            let Some(mut pending) = self.pending.remove(&name) else {
+                continue;
+            };
+            self.run(&mut pending, now).await;
+            self.publish(&pending).await;
+            self.pending.insert(name, pending);
+        }
+    }
+
+    async fn run(&self, pending: &mut Pending, now: DateTime<Local>) {
+        let schedule = &pending.schedule;
+        let next_regular = next_occurrence(schedule, &now);
+        let robot = self
+            .neato
+            .robots()
+            .into_iter()
+            .find(|r| r.id == schedule.robot);
+        let condition = schedule
+            .condition_topic
+            .as_ref()
+            .and_then(|topic| self.mqtt_client.condition(topic));
+
+        pending.deferred = false;
+        pending.next_run = next_regular;
+
+        if let Some(reason) = blocked(schedule, condition.as_deref(), robot.as_ref()) {
+            let retry = schedule
+                .defer
+                .map(|minutes| now + chrono::Duration::minutes(minutes as i64))
+                .filter(|retry| next_regular.map_or(true, |next| *retry < next));
+            match retry {
+                Some(retry) => {
+                    info!(
+                        "Schedule {} held back ({}), trying again at {}",
+                        schedule.name,
+                        reason,
+                        retry.to_rfc2822()
+                    );
+                    pending.next_run = Some(retry);
+                    pending.deferred = true;
+                }
+                None => info!("Schedule {} skipped ({})", schedule.name, reason),
+            }
+            return;
+        }
+
+        if robot.is_none() {
+            warn!(
+                "Schedule {}: no robot found with id \"{}\"",
+                schedule.name, schedule.robot
+            );
+            return;
+        }
+
+        info!("Schedule {} starts {}", schedule.name, schedule.robot);
+        let action = SendAction {
+            id: schedule.robot.clone(),
+            action: RobotCmd::StartCleaningWith(schedule.params.clone()),
+        };
+        if let Err(err) = self.neato.handle_action(action).await {
+            error!(
+                "Schedule {} could not start the robot: {}",
+                schedule.name, err
+            );
+        }
+    }
+
+    /// `{topic}/schedules/{name}`, None if the robot is unknown and `topic` needs more than
+    /// its `{id}`
+    fn topic(&self, schedule: &ScheduleSettings) -> Option<String> {
+        let mqtt_settings = self.mqtt_client.settings();
+        let values = match self.neato.robots().iter().find(|r| r.id == schedule.robot) {
+            Some(robot) => robot_values(robot, &mqtt_settings),
+            None => {
+                let mut values = bridge_values(&mqtt_settings);
+                values.insert(Placeholder::Id, schedule.robot.clone());
+                values
+            }
+        };
+        fill_topic(&mqtt_settings.topic, &values)
+            .map(|topic| format!("{}/schedules/{}", topic, schedule.name))
+    }
+
+    /// Publishes the next run, retained
+    async fn publish(&self, pending: &Pending) {
+        let Some(topic) = self.topic(&pending.schedule) else {
+            warn!(
+                "Schedule {}: robot {} not found, its next run is not published",
+                pending.schedule.name, pending.schedule.robot
+            );
+            return;
+        };
+        let next_run = NextRun {
+            name: &pending.schedule.name,
+            cron: &pending.schedule.cron,
+            next_run: pending.next_run,
+            deferred: pending.deferred,
+        };
+        let payload = match serde_json::to_string(&next_run) {
+            Ok(payload) => payload,
+            Err(err) => {
+                error!("Could not serialize the next run: {}", err);
+                return;
+            }
+        };
+        if let Err(err) = self
+            .mqtt_client
+            .client()
+            .publish(
+                topic,
+                self.mqtt_client.settings().qos.state.into(),
+                true,
+                payload,
+            )
+            .await
+        {
+            error!("Could not publish the next run: {}", err);
+        }
+    }
+
+    /// Removes the retained next run of a schedule that no longer exists
+    async fn clear(&self, schedule: &ScheduleSettings) {
+        let Some(topic) = self.topic(schedule) else {
+            return;
+        };
+        if let Err(err) = self
+            .mqtt_client
+            .client()
+            .publish(
+                topic,
+                self.mqtt_client.settings().qos.state.into(),
+                true,
+                Vec::new(),
+            )
+            .await
+        {
+            error!("Could not clear the next run: {}", err);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::TimeZone;
+
+    use super::*;
+    use crate::{
+        backend::fake,
+        neato_types::{HouseCleaningParams, RobotState},
+    };
+
+    fn schedule() -> ScheduleSettings {
+        ScheduleSettings {
+            name: String::from("mornings"),
+            robot: String::from("Vacuum"),
+            cron: String::from("0 9 * * 1-5"),
+            params: HouseCleaningParams::default(),
+            condition_topic: Some(String::from("home/presence/anyone_home")),
+            condition_value: None,
+            min_charge: Some(50),
+            defer: None,
+        }
+    }
+
+    fn robot(charge: i8) -> Robot {
+        Robot {
+            state: Some(fake::state(RobotState::Idle, true, charge)),
+            ..fake::robot("Vacuum")
+        }
+    }
+
+    #[test]
+    fn next_occurrence_skips_the_weekend() {
+        // A Friday after 9:00
+        let friday = Local.with_ymd_and_hms(2023, 11, 17, 10, 0, 0).unwrap();
+
+        let next = next_occurrence(&schedule(), &friday).unwrap();
+
+        assert_eq!(next, Local.with_ymd_and_hms(2023, 11, 20, 9, 0, 0).unwrap());
+    }
+
+    #[test]
+    fn held_back_by_condition_topic() {
+        let robot = robot(100);
+
+        assert!(blocked(&schedule(), Some("true"), Some(&robot)).is_some());
+        assert!(blocked(&schedule(), Some("false"), Some(&robot)).is_none());
+        assert!(blocked(&schedule(), None, Some(&robot)).is_none());
+    }
+
+    #[test]
+    fn held_back_by_low_charge() {
+        assert!(blocked(&schedule(), None, Some(&robot(49))).is_some());
+        assert!(blocked(&schedule(), None, Some(&robot(50))).is_none());
+    }
+}
@@ -0,0 +1,66 @@
+use std::fmt;
+
+use serde::Deserialize;
+
+/// A value that must not end up in logs or published payloads. It is redacted in `Debug`
+/// and `Display` and deliberately does not implement `Serialize`, use [`Secret::expose`]
+/// where the value is really needed.
+#[derive(Clone, Default, Deserialize, PartialEq)]
+#[serde(transparent)]
+pub struct Secret<T>(T);
+
+impl<T> Secret<T> {
+    pub fn new(value: T) -> Secret<T> {
+        Secret(value)
+    }
+
+    pub fn expose(&self) -> &T {
+        &self.0
+    }
+}
+
+impl<T> fmt::Debug for Secret<T> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Secret([redacted])")
+    }
+}
+
+impl<T> fmt::Display for Secret<T> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "[redacted]")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{backend::fake, settings::NeatoAccount};
+
+    #[test]
+    fn value_is_redacted() {
+        let secret = Secret::new(String::from("hunter2"));
+
+        assert_eq!(format!("{:?}", secret), "Secret([redacted])");
+        assert_eq!(secret.to_string(), "[redacted]");
+        assert_eq!(secret.expose(), "hunter2");
+    }
+
+    #[test]
+    fn passwords_are_redacted_in_settings() {
+        let mut settings = fake::settings();
+        settings.neato.password = Some(Secret::new(String::from("hunter2")));
+        settings.neato.accounts.push(NeatoAccount {
+            name: Some(String::from("second")),
+            email: String::from("second@example.com"),
+            password: Secret::new(String::from("correct horse")),
+            topic_prefix: String::new(),
+            request_interval: None,
+        });
+
+        let printed = format!("{:?}", settings);
+
+        assert!(!printed.contains("hunter2"));
+        assert!(!printed.contains("correct horse"));
+        assert!(printed.contains("[redacted]"));
+    }
+}
@@ -1,18 +1,95 @@
-use std::env;
+use std::{collections::HashMap, env, fs, time::Duration};
 
+use eyre::WrapErr;
 use log::log_enabled;
 use serde::Deserialize;
 
-#[derive(Clone, Deserialize, Debug)]
-pub struct NeatoSettings {
+use crate::{
+    connection::{MqttVersion, Qos},
+    neato_types::HouseCleaningParams,
+    secret::Secret,
+    topics::{bridge_values, fill_topic, TopicId, TopicStyle},
+    validation::{self, settings_file},
+};
+
+#[derive(Clone, Deserialize, Debug, PartialEq)]
+pub struct NeatoAccount {
+    /// Used in logs, defaults to the email address
+    pub name: Option<String>,
     pub email: String,
-    pub password: String,
-    pub poll_interval: u16, // seconds
-    pub cache_timeout: u16, // seconds
+    pub password: Secret<String>,
+    /// Prepended to the `{id}` of every robot on this account
+    #[serde(default)]
+    pub topic_prefix: String,
+    pub request_interval: Option<u64>, // milliseconds
+}
+
+impl NeatoAccount {
+    pub fn name(&self) -> &str {
+        self.name.as_deref().unwrap_or(&self.email)
+    }
+}
+
+/// Where robots come from
+#[derive(Clone, Copy, Deserialize, Debug, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum Backend {
+    /// Robots on the configured Neato accounts
+    Cloud,
+    /// Simulated robots, see `SimulatorSettings`
+    Simulator,
+}
+
+#[derive(Clone, Deserialize, Debug, PartialEq)]
+pub struct NeatoSettings {
+    pub backend: Backend,
+    pub email: Option<String>,
+    pub password: Option<Secret<String>>,
+    #[serde(default)]
+    pub accounts: Vec<NeatoAccount>,
+    pub request_interval: u64, // milliseconds, per account
+    pub request_timeout: u64,  // seconds
+    /// How often a failed state request is tried again, movement commands are never retried
+    pub retries: u32,
+    pub retry_delay: u64, // milliseconds, doubled on every retry
+    /// State requests in flight at the same time
+    pub max_concurrent_requests: usize,
+    pub poll_interval: u16,         // seconds
+    pub offline_poll_interval: u16, // seconds
+    pub cache_timeout: u16,         // seconds
     pub decode_state: bool,
     pub dry_run: bool,
 }
 
+impl NeatoSettings {
+    /// All configured accounts, the top level `email` and `password` count as the first one.
+    /// The simulator has a single account of its own.
+    pub fn accounts(&self) -> Vec<NeatoAccount> {
+        if self.backend == Backend::Simulator {
+            return vec![NeatoAccount {
+                name: Some(String::from("simulator")),
+                email: String::from("simulator"),
+                password: Secret::new(String::new()),
+                topic_prefix: String::new(),
+                request_interval: Some(0),
+            }];
+        }
+
+        let mut accounts = Vec::new();
+        if let (Some(email), Some(password)) = (&self.email, &self.password) {
+            accounts.push(NeatoAccount {
+                name: None,
+                email: email.clone(),
+                password: password.clone(),
+                topic_prefix: String::new(),
+                request_interval: None,
+            });
+        }
+        accounts.extend(self.accounts.iter().cloned());
+        accounts
+    }
+}
+
 fn default_poll_interval() -> u16 {
     if log_enabled!(log::Level::Debug) {
         5 // seconds
@@ -29,51 +106,434 @@ fn default_cache_timeout() -> u16 {
     }
 }
 
-#[derive(Clone, Deserialize, Debug)]
+/// QoS per kind of message
+#[derive(Clone, Deserialize, Debug, PartialEq)]
+pub struct QosSettings {
+    /// Robot states, availability, request logs and next runs of schedules
+    pub state: Qos,
+    /// Subscriptions to the set and condition topics, and command results
+    pub commands: Qos,
+    /// Events and notifications
+    pub events: Qos,
+    /// Homie device attributes
+    pub discovery: Qos,
+}
+
+#[derive(Clone, Deserialize, Debug, PartialEq)]
+pub struct RetainSettings {
+    pub state: bool,
+    pub availability: bool,
+    pub events: bool,
+}
+
+#[derive(Clone, Deserialize, Debug, PartialEq)]
 pub struct MqttSettings {
     pub id: String,
     pub host: String,
     pub port: u16,
     pub topic: String,
     pub set_topic: String,
+    /// Commands sent here go to all robots
+    pub broadcast_topic: Option<String>,
+    pub topic_id: TopicId,
+    /// `json` or `homie`
+    pub topic_style: TopicStyle,
+    /// `3.1.1` or `5`
+    pub version: MqttVersion,
+    /// Seconds until undelivered state messages expire, 0 for never, MQTT 5 only
+    pub state_expiry: u32,
+    pub keep_alive: u64, // seconds, 0 disables pings (MQTT 3.1.1 only, MQTT 5 needs at least 5)
+    /// Start without the subscriptions and queued messages of the previous connection
+    pub clean_session: bool,
+    /// Seconds the broker keeps a persistent session after a disconnect, MQTT 5 only
+    pub session_expiry: u32,
+    /// Outgoing QoS 1 and 2 messages not yet acknowledged by the broker
+    pub inflight: u16,
+    /// Outgoing messages queued for the connection
+    pub channel_capacity: usize,
+    pub qos: QosSettings,
+    pub retain: RetainSettings,
+    /// Fixed `{id}` per robot, keyed by serial, MAC address or name
+    #[serde(default)]
+    pub aliases: HashMap<String, String>,
+    /// Publish the recent requests of each robot to `{topic}/requests`
+    pub publish_request_log: bool,
 }
 
 impl MqttSettings {
-    // pub fn get_topic_with_wildcard(&self) -> String {
-    //     // Return the topic with `{id}` replaced with `+`
-    //     self.topic.replace("{id}", "+")
-    // }
-    pub fn get_topic_with_id_as_set(&self) -> String {
-        self.topic.replace("{id}", "set")
+    /// Topic for commands to all robots, `topic` with `set` as `{id}` unless configured
+    pub fn get_broadcast_topic(&self) -> String {
+        let template = self
+            .broadcast_topic
+            .clone()
+            .unwrap_or_else(|| self.topic.replace("{id}", "set"));
+        fill_topic(&template, &bridge_values(self)).unwrap_or(template)
     }
-    // pub fn get_topic_without_id(&self) -> String {
-    //     // Return the topic with `{id}` removed
-    //     self.topic.replace("{id}", "")
-    // }
-    pub fn get_set_topic_with_wildcard(&self) -> String {
-        // Return the topic with `{id}` replaced with `+`
-        self.set_topic.replace("{id}", "+")
+}
+
+#[derive(Clone, Deserialize, Debug, PartialEq)]
+pub struct HttpSettings {
+    pub enabled: bool,
+    pub host: String,
+    pub port: u16,
+}
+
+#[derive(Clone, Deserialize, Debug, PartialEq)]
+pub struct SimulatorSettings {
+    /// Names of the simulated robots
+    pub robots: Vec<String>,
+    pub battery_drain: f64, // percent per minute while cleaning
+    pub charge_rate: f64,   // percent per minute while docked
+    pub docking_time: u64,  // seconds
+    /// Chance of raising an alert, such as a full dust bin, on each state request
+    pub alert_probability: f64,
+    /// Speeds up simulated time, 60 makes a minute pass every second
+    pub time_scale: f64,
+}
+
+#[derive(Clone, Deserialize, Debug, PartialEq)]
+pub struct EventSettings {
+    /// A `battery_low` event is published when the charge drops below this percentage
+    pub battery_threshold: i8,
+}
+
+#[derive(Clone, Deserialize, Debug, PartialEq)]
+pub struct NotificationSettings {
+    /// Where notifications of matching rules are published, `{id}` is optional
+    pub topic: String,
+    pub cooldown: u64, // seconds between notifications of the same rule and robot
+}
+
+/// Checked against the state of every robot after each poll, see `rules::Condition`
+#[derive(Clone, Deserialize, Debug, PartialEq)]
+pub struct RuleSettings {
+    pub name: String,
+    /// e.g. `charge < 15 && !is_docked` or `state == Error for > 10 min`
+    pub when: String,
+    /// Notification text, may contain `{rule}`, `{name}` and `{id}`
+    pub message: Option<String>,
+    /// Overrides `notifications.cooldown`
+    pub cooldown: Option<u64>,
+}
+
+/// Robots addressed together on `{group}/set`
+#[derive(Clone, Deserialize, Debug, PartialEq)]
+#[serde(untagged)]
+pub enum GroupSettings {
+    /// `upstairs = ["Vacuum", "Botvac2"]`
+    Robots(Vec<String>),
+    /// `upstairs = { robots = ["Vacuum", "Botvac2"], sequential = true }`
+    Table(GroupTable),
+}
+
+#[derive(Clone, Deserialize, Debug, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct GroupTable {
+    pub robots: Vec<String>,
+    /// Start the next robot only after the previous one is back on its base
+    #[serde(default)]
+    pub sequential: bool,
+    /// Minutes a robot has to leave its base before the next one is started anyway,
+    /// defaults to 10
+    pub start_timeout: Option<u64>,
+}
+
+impl GroupSettings {
+    /// `{id}` of the robots in the group, in the order they are started
+    pub fn robots(&self) -> &[String] {
+        match self {
+            GroupSettings::Robots(robots) => robots,
+            GroupSettings::Table(table) => &table.robots,
+        }
     }
+
+    pub fn sequential(&self) -> bool {
+        matches!(self, GroupSettings::Table(table) if table.sequential)
+    }
+
+    /// How long a sequential start waits for a robot to leave its base
+    pub fn start_timeout(&self) -> Duration {
+        let minutes = match self {
+            GroupSettings::Table(table) => table.start_timeout,
+            GroupSettings::Robots(_) => None,
+        };
+        Duration::from_secs(minutes.unwrap_or(10) * 60)
+    }
+}
+
+/// Starts a robot at the times of a cron expression, see `scheduler`
+#[derive(Clone, Deserialize, Debug, PartialEq)]
+pub struct ScheduleSettings {
+    /// Used in the topic the next run is published to
+    pub name: String,
+    /// `{id}` of the robot to start
+    pub robot: String,
+    /// Cron expression in local time, e.g. `0 9 * * 1-5`
+    pub cron: String,
+    #[serde(default)]
+    pub params: HouseCleaningParams,
+    /// The run is held back while this MQTT topic has the value `condition_value`
+    pub condition_topic: Option<String>,
+    /// Defaults to `true`
+    pub condition_value: Option<String>,
+    /// The run is held back while the charge is below this percentage
+    pub min_charge: Option<i8>,
+    /// Minutes to wait before trying a held back run again, skipped until the next run if unset
+    pub defer: Option<u64>,
 }
 
-#[derive(Clone, Deserialize, Debug)]
+#[derive(Clone, Deserialize, Debug, PartialEq)]
 pub struct Settings {
     pub neato: NeatoSettings,
     pub mqtt: MqttSettings,
+    pub http: HttpSettings,
+    pub events: EventSettings,
+    pub notifications: NotificationSettings,
+    #[serde(default)]
+    pub rules: Vec<RuleSettings>,
+    #[serde(default)]
+    pub schedules: Vec<ScheduleSettings>,
+    #[serde(default)]
+    pub groups: HashMap<String, GroupSettings>,
+    pub simulator: SimulatorSettings,
+}
+
+/// Every setting can be overridden with e.g. `NEATO_MQTT__NEATO__POLL_INTERVAL`
+pub const ENV_PREFIX: &str = "NEATO_MQTT";
+
+/// Environment variable that overrides the setting `key`, e.g. `mqtt.host`
+pub fn env_var_name(key: &str) -> String {
+    format!("{}__{}", ENV_PREFIX, key.replace('.', "__")).to_uppercase()
+}
+
+/// Settings from the environment. A variable with a `_FILE` suffix, e.g.
+/// `NEATO_MQTT__NEATO__PASSWORD_FILE`, is read from the file it points to, for use
+/// with Docker and Kubernetes secrets.
+fn environment() -> eyre::Result<config::Environment> {
+    let prefix = format!("{}__", ENV_PREFIX);
+    let mut vars = config::Map::new();
+    let mut files = config::Map::new();
+    for (key, value) in env::vars().filter(|(key, _)| key.starts_with(&prefix)) {
+        match key.strip_suffix("_FILE") {
+            Some(key) => {
+                let contents = fs::read_to_string(&value)
+                    .wrap_err_with(|| format!("Could not read {}_FILE from {}", key, value))?;
+                files.insert(
+                    key.to_string(),
+                    contents.trim_end_matches(['\r', '\n']).to_string(),
+                );
+            }
+            None => {
+                vars.insert(key, value);
+            }
+        }
+    }
+    // A value set directly takes precedence over one read from a file
+    for (key, value) in files {
+        vars.entry(key).or_insert(value);
+    }
+
+    Ok(config::Environment::with_prefix(ENV_PREFIX)
+        .separator("__")
+        .source(Some(vars)))
 }
 
-pub fn read_settings() -> Result<Settings, config::ConfigError> {
-    config::Config::builder()
-        .add_source(config::File::with_name("Settings"))
+/// Reads the settings from `path`, or from the optional `Settings.toml` if no path is given,
+/// with overrides from the environment
+pub fn read_settings(path: Option<&str>) -> eyre::Result<Settings> {
+    let file = path.unwrap_or("Settings");
+    let config = config::Config::builder()
+        .add_source(config::File::with_name(file).required(path.is_some()))
         .set_default("mqtt.host", "localhost")?
         .set_default("mqtt.port", 1883)?
         .set_default("mqtt.topic", "home/devices/neato/{id}")?
         .set_default("mqtt.set_topic", "home/devices/neato/{id}/set")?
+        .set_default("mqtt.topic_id", "name")?
+        .set_default("mqtt.topic_style", "json")?
+        .set_default("mqtt.version", "3.1.1")?
+        .set_default("mqtt.state_expiry", 300)?
+        .set_default("mqtt.keep_alive", 5)?
+        .set_default("mqtt.clean_session", false)?
+        .set_default("mqtt.session_expiry", 60 * 60)?
+        .set_default("mqtt.inflight", 100)?
+        .set_default("mqtt.channel_capacity", 10)?
+        .set_default("mqtt.qos.state", 0)?
+        .set_default("mqtt.qos.commands", 1)?
+        .set_default("mqtt.qos.events", 0)?
+        .set_default("mqtt.qos.discovery", 1)?
+        .set_default("mqtt.retain.state", false)?
+        .set_default("mqtt.retain.availability", true)?
+        .set_default("mqtt.retain.events", false)?
+        .set_default("mqtt.publish_request_log", false)?
+        .set_default("neato.backend", "cloud")?
+        .set_default("neato.request_interval", 250)?
+        .set_default("neato.request_timeout", 10)?
+        .set_default("neato.retries", 2)?
+        .set_default("neato.retry_delay", 500)?
+        .set_default("neato.max_concurrent_requests", 4)?
         .set_default("neato.poll_interval", default_poll_interval())?
+        .set_default("neato.offline_poll_interval", 5 * 60)?
         .set_default("neato.cache_timeout", default_cache_timeout())?
         .set_default("neato.decode_state", false)?
         .set_default("neato.dry_run", false)?
+        .set_default("http.enabled", false)?
+        .set_default("http.host", "127.0.0.1")?
+        .set_default("http.port", 8080)?
+        .set_default("events.battery_threshold", 20)?
+        .set_default(
+            "notifications.topic",
+            "home/devices/neato/{id}/notifications",
+        )?
+        .set_default("notifications.cooldown", 60 * 60)?
+        .set_default("simulator.robots", vec!["Vacuum"])?
+        .set_default("simulator.battery_drain", 1.0)?
+        .set_default("simulator.charge_rate", 2.0)?
+        .set_default("simulator.docking_time", 30)?
+        .set_default("simulator.alert_probability", 0.01)?
+        .set_default("simulator.time_scale", 1.0)?
         .set_override_option("mqtt.host", env::var("MQTT_HOST").ok())?
-        .build()?
-        .try_deserialize::<Settings>()
+        .add_source(environment()?)
+        .build()?;
+
+    Ok(validation::validate(
+        config,
+        settings_file(file).as_deref(),
+    )?)
+}
+
+#[cfg(test)]
+pub mod tests {
+    use std::sync::{Mutex, MutexGuard};
+
+    use super::*;
+
+    /// Held while a test changes or depends on the environment, which all tests share
+    static ENV_LOCK: Mutex<()> = Mutex::new(());
+
+    /// Environment variables that are set until it is dropped
+    pub struct ScopedEnv {
+        names: Vec<String>,
+        _lock: MutexGuard<'static, ()>,
+    }
+
+    impl ScopedEnv {
+        pub fn new(vars: &[(&str, &str)]) -> ScopedEnv {
+            let lock = ENV_LOCK.lock().unwrap_or_else(|err| err.into_inner());
+            for (name, value) in vars {
+                env::set_var(name, value);
+            }
+            ScopedEnv {
+                names: vars.iter().map(|(name, _)| name.to_string()).collect(),
+                _lock: lock,
+            }
+        }
+    }
+
+    impl Drop for ScopedEnv {
+        fn drop(&mut self) {
+            for name in &self.names {
+                env::remove_var(name);
+            }
+        }
+    }
+
+    /// Temporary file named after the test, removed when dropped
+    struct TempFile(std::path::PathBuf);
+
+    impl TempFile {
+        fn new(name: &str, contents: &str) -> TempFile {
+            let path = env::temp_dir().join(format!("neato-mqtt-{}-{}", std::process::id(), name));
+            fs::write(&path, contents).unwrap();
+            TempFile(path)
+        }
+
+        fn path(&self) -> &str {
+            self.0.to_str().unwrap()
+        }
+    }
+
+    impl Drop for TempFile {
+        fn drop(&mut self) {
+            let _ = fs::remove_file(&self.0);
+        }
+    }
+
+    const SETTINGS: &str = r#"[mqtt]
+id = "neato-mqtt"
+host = "file.local"
+
+[neato]
+email = "test@example.com"
+password = "from-file"
+"#;
+
+    #[test]
+    fn environment_overrides_the_file() {
+        let file = TempFile::new("env-override.toml", SETTINGS);
+        let _env = ScopedEnv::new(&[
+            ("NEATO_MQTT__MQTT__HOST", "env.local"),
+            ("NEATO_MQTT__NEATO__POLL_INTERVAL", "30"),
+        ]);
+
+        let settings = read_settings(Some(file.path())).unwrap();
+
+        assert_eq!(settings.mqtt.host, "env.local");
+        assert_eq!(settings.mqtt.id, "neato-mqtt");
+        assert_eq!(settings.neato.poll_interval, 30);
+    }
+
+    #[test]
+    fn nested_settings_are_separated_by_double_underscores() {
+        let file = TempFile::new("env-nested.toml", SETTINGS);
+        let _env = ScopedEnv::new(&[
+            ("NEATO_MQTT__MQTT__QOS__STATE", "1"),
+            ("NEATO_MQTT__MQTT__RETAIN__EVENTS", "true"),
+        ]);
+
+        let settings = read_settings(Some(file.path())).unwrap();
+
+        assert_eq!(settings.mqtt.qos.state, Qos::AtLeastOnce);
+        assert!(settings.mqtt.retain.events);
+    }
+
+    #[test]
+    fn values_are_read_from_files() {
+        let file = TempFile::new("env-file.toml", SETTINGS);
+        let password = TempFile::new("env-password", "from-secret-file\n");
+        let _env = ScopedEnv::new(&[("NEATO_MQTT__NEATO__PASSWORD_FILE", password.path())]);
+
+        let settings = read_settings(Some(file.path())).unwrap();
+
+        assert_eq!(
+            settings.neato.password.unwrap().expose(),
+            "from-secret-file"
+        );
+    }
+
+    #[test]
+    fn plain_variable_takes_precedence_over_file() {
+        let file = TempFile::new("env-precedence.toml", SETTINGS);
+        let password = TempFile::new("env-precedence-password", "from-secret-file");
+        let _env = ScopedEnv::new(&[
+            ("NEATO_MQTT__NEATO__PASSWORD_FILE", password.path()),
+            ("NEATO_MQTT__NEATO__PASSWORD", "from-variable"),
+        ]);
+
+        let settings = read_settings(Some(file.path())).unwrap();
+
+        assert_eq!(settings.neato.password.unwrap().expose(), "from-variable");
+    }
+
+    #[test]
+    fn missing_secret_file_is_an_error() {
+        let file = TempFile::new("env-missing.toml", SETTINGS);
+        let _env = ScopedEnv::new(&[(
+            "NEATO_MQTT__NEATO__PASSWORD_FILE",
+            "/nonexistent/neato-password",
+        )]);
+
+        let err = read_settings(Some(file.path())).unwrap_err();
+
+        assert!(err.to_string().contains("NEATO_MQTT__NEATO__PASSWORD_FILE"));
+    }
 }
@@ -0,0 +1,431 @@
+use std::collections::HashMap;
+
+use serde::Deserialize;
+
+use crate::{neato_types::Robot, settings::MqttSettings};
+
+/// A placeholder in a topic template
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum Placeholder {
+    Id,
+    Serial,
+    Model,
+    Mac,
+    Account,
+    /// `mqtt.id` of this bridge
+    BridgeId,
+}
+
+/// Placeholders that may be used in topic templates
+const PLACEHOLDERS: &[(&str, Placeholder)] = &[
+    ("{id}", Placeholder::Id),
+    ("{serial}", Placeholder::Serial),
+    ("{model}", Placeholder::Model),
+    ("{mac}", Placeholder::Mac),
+    ("{account}", Placeholder::Account),
+    ("{bridge_id}", Placeholder::BridgeId),
+];
+
+impl Placeholder {
+    fn name(self) -> &'static str {
+        PLACEHOLDERS
+            .iter()
+            .find(|(_, placeholder)| *placeholder == self)
+            .map_or("", |(name, _)| *name)
+    }
+}
+
+/// Values of the placeholders of a topic template
+pub type TopicValues = HashMap<Placeholder, String>;
+
+/// Which robot property is used as `{id}` in topics
+#[derive(Clone, Copy, Deserialize, Debug, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum TopicId {
+    /// Display name as set in the Neato app
+    Name,
+    /// Display name, lowercased with everything but letters and digits replaced by `-`
+    Slug,
+    Serial,
+    Mac,
+}
+
+/// Layout of the state topics
+#[derive(Clone, Copy, Deserialize, Debug, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum TopicStyle {
+    /// The whole robot as JSON on `topic`
+    Json,
+    /// A Homie 4 device per robot under `homie/`, see `homie`
+    Homie,
+}
+
+/// Replaces characters that are not allowed in, or have special meaning in, an MQTT topic level
+pub fn sanitize_topic_level(level: &str) -> String {
+    let sanitized: String = level
+        .chars()
+        .map(|c| match c {
+            '/' | '+' | '#' => '_',
+            c if c.is_control() => '_',
+            c => c,
+        })
+        .collect();
+
+    if sanitized.is_empty() {
+        String::from("_")
+    } else {
+        sanitized
+    }
+}
+
+pub fn slugify(name: &str) -> String {
+    let mut slug = String::new();
+    for c in name.chars().flat_map(char::to_lowercase) {
+        if c.is_alphanumeric() {
+            slug.push(c);
+        } else if !slug.is_empty() && !slug.ends_with('-') {
+            slug.push('-');
+        }
+    }
+    slug.trim_end_matches('-').to_string()
+}
+
+/// Topic `{id}` of a robot. An alias keyed by serial, MAC address or name takes
+/// precedence over the configured `topic_id`.
+pub fn robot_topic_id(robot: &Robot, topic_prefix: &str, mqtt_settings: &MqttSettings) -> String {
+    let aliases = &mqtt_settings.aliases;
+    let id = match [&robot.serial, &robot.mac_address, &robot.name]
+        .iter()
+        .find_map(|key| aliases.get(key.as_str()))
+    {
+        Some(alias) => alias.clone(),
+        None => match mqtt_settings.topic_id {
+            TopicId::Name => robot.name.clone(),
+            TopicId::Slug => slugify(&robot.name),
+            TopicId::Serial => robot.serial.clone(),
+            TopicId::Mac => robot.mac_address.clone(),
+        },
+    };
+
+    sanitize_topic_level(&format!("{}{}", topic_prefix, id))
+}
+
+/// Values known without a robot
+pub fn bridge_values(mqtt_settings: &MqttSettings) -> TopicValues {
+    TopicValues::from([(
+        Placeholder::BridgeId,
+        sanitize_topic_level(&mqtt_settings.id),
+    )])
+}
+
+/// Values of all placeholders for a robot
+pub fn robot_values(robot: &Robot, mqtt_settings: &MqttSettings) -> TopicValues {
+    let mut values = bridge_values(mqtt_settings);
+    values.extend([
+        (Placeholder::Id, robot.id.clone()),
+        (Placeholder::Serial, sanitize_topic_level(&robot.serial)),
+        (Placeholder::Model, sanitize_topic_level(&robot.model)),
+        (Placeholder::Mac, sanitize_topic_level(&robot.mac_address)),
+        (Placeholder::Account, sanitize_topic_level(&robot.account)),
+    ]);
+    values
+}
+
+/// Topic of a template, None if the template is invalid or a placeholder has no value
+pub fn fill_topic(template: &str, values: &TopicValues) -> Option<String> {
+    TopicTemplate::parse(template).ok()?.fill(values)
+}
+
+/// Topic of a robot from a template such as `mqtt.topic`
+pub fn robot_topic(template: &str, robot: &Robot, mqtt_settings: &MqttSettings) -> String {
+    fill_topic(template, &robot_values(robot, mqtt_settings))
+        .unwrap_or_else(|| template.replace("{id}", &robot.id))
+}
+
+#[derive(Clone, Debug, PartialEq)]
+enum Segment {
+    Literal(String),
+    Placeholder(Placeholder),
+}
+
+/// A topic template such as `home/devices/neato/{id}/set`, parsed into topic levels so that
+/// placeholders are only ever taken from their own level
+#[derive(Clone, Debug, PartialEq)]
+pub struct TopicTemplate {
+    levels: Vec<Vec<Segment>>,
+}
+
+fn parse_level(level: &str) -> Result<Vec<Segment>, String> {
+    let mut segments = Vec::new();
+    let mut rest = level;
+    while let Some(start) = rest.find('{') {
+        if start > 0 {
+            segments.push(Segment::Literal(rest[..start].to_string()));
+        }
+        let end = rest[start..]
+            .find('}')
+            .map_or(rest.len(), |e| start + e + 1);
+        let placeholder = &rest[start..end];
+        match PLACEHOLDERS.iter().find(|(name, _)| *name == placeholder) {
+            Some((_, placeholder)) => segments.push(Segment::Placeholder(*placeholder)),
+            None => {
+                return Err(format!(
+                    "unknown placeholder `{}`, expected one of {}",
+                    placeholder,
+                    PLACEHOLDERS
+                        .iter()
+                        .map(|(name, _)| *name)
+                        .collect::<Vec<_>>()
+                        .join(", ")
+                ))
+            }
+        }
+        rest = &rest[end..];
+    }
+    if !rest.is_empty() {
+        segments.push(Segment::Literal(rest.to_string()));
+    }
+    Ok(segments)
+}
+
+/// Text of the segments, with placeholders replaced by their values, None if one is unknown
+fn fill_level(segments: &[Segment], values: &TopicValues) -> Option<String> {
+    segments
+        .iter()
+        .map(|segment| match segment {
+            Segment::Literal(literal) => Some(literal.as_str()),
+            Segment::Placeholder(placeholder) => values.get(placeholder).map(String::as_str),
+        })
+        .collect()
+}
+
+impl TopicTemplate {
+    pub fn parse(template: &str) -> Result<TopicTemplate, String> {
+        let levels = template
+            .split('/')
+            .map(parse_level)
+            .collect::<Result<_, _>>()?;
+        Ok(TopicTemplate { levels })
+    }
+
+    pub fn placeholders(&self) -> impl Iterator<Item = Placeholder> + '_ {
+        self.levels
+            .iter()
+            .flatten()
+            .filter_map(|segment| match segment {
+                Segment::Placeholder(placeholder) => Some(*placeholder),
+                Segment::Literal(_) => None,
+            })
+    }
+
+    /// The topic, None if a placeholder has no value
+    pub fn fill(&self, values: &TopicValues) -> Option<String> {
+        self.levels
+            .iter()
+            .map(|level| fill_level(level, values))
+            .collect::<Option<Vec<_>>>()
+            .map(|levels| levels.join("/"))
+    }
+
+    /// Subscription for all topics of the template, `+` stands in for levels with a
+    /// placeholder that has no value in `known`
+    pub fn subscription(&self, known: &TopicValues) -> String {
+        self.levels
+            .iter()
+            .map(|level| fill_level(level, known).unwrap_or_else(|| String::from("+")))
+            .collect::<Vec<_>>()
+            .join("/")
+    }
+
+    /// Fails if a topic level has more than one placeholder without a value in `known`,
+    /// the topics of such a template can't be matched unambiguously
+    pub fn check_reversible(&self, known: &TopicValues) -> Result<(), String> {
+        for level in self.levels.iter() {
+            let unknown: Vec<&str> = level
+                .iter()
+                .filter_map(|segment| match segment {
+                    Segment::Placeholder(p) if !known.contains_key(p) => Some(p.name()),
+                    _ => None,
+                })
+                .collect();
+            if unknown.len() > 1 {
+                return Err(format!(
+                    "{} must not share a topic level, topics could not be told apart",
+                    unknown
+                        .iter()
+                        .map(|name| format!("`{}`", name))
+                        .collect::<Vec<_>>()
+                        .join(" and ")
+                ));
+            }
+        }
+        Ok(())
+    }
+
+    /// Values of the placeholders in a topic that matches the template, `known` values
+    /// have to match as well and are included
+    pub fn match_values(&self, topic: &str, known: &TopicValues) -> Option<TopicValues> {
+        let levels: Vec<&str> = topic.split('/').collect();
+        if levels.len() != self.levels.len() {
+            return None;
+        }
+        let mut values = known.clone();
+        for (level, segments) in levels.into_iter().zip(self.levels.iter()) {
+            let unknown = segments.iter().position(|segment| match segment {
+                Segment::Placeholder(placeholder) => !values.contains_key(placeholder),
+                Segment::Literal(_) => false,
+            });
+            match unknown {
+                None => {
+                    if fill_level(segments, &values)? != level {
+                        return None;
+                    }
+                }
+                Some(i) => {
+                    let Segment::Placeholder(placeholder) = segments[i] else {
+                        return None;
+                    };
+                    // Other unknown placeholders in the level are rejected by `fill_level`
+                    let value = level
+                        .strip_prefix(fill_level(&segments[..i], &values)?.as_str())?
+                        .strip_suffix(fill_level(&segments[i + 1..], &values)?.as_str())
+                        .filter(|value| !value.is_empty())?;
+                    values.insert(placeholder, value.to_string());
+                }
+            }
+        }
+        Some(values)
+    }
+
+    /// `{id}` of a topic that matches the template
+    pub fn match_id(&self, topic: &str, known: &TopicValues) -> Option<String> {
+        self.match_values(topic, known)?.remove(&Placeholder::Id)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::backend::fake;
+
+    #[test]
+    fn topic_levels_are_sanitized() {
+        assert_eq!(sanitize_topic_level("Up/Down #1+2"), "Up_Down _1_2");
+        assert_eq!(sanitize_topic_level("tab\there"), "tab_here");
+        assert_eq!(sanitize_topic_level(""), "_");
+    }
+
+    #[test]
+    fn names_are_slugified() {
+        assert_eq!(slugify("Living Room"), "living-room");
+        assert_eq!(slugify("  Küche / 2nd floor!"), "küche-2nd-floor");
+        assert_eq!(slugify("--"), "");
+    }
+
+    #[test]
+    fn topic_id_follows_the_setting() {
+        let mut robot = fake::robot("Living Room/2");
+        robot.serial = String::from("OPS12345-4A0011223344");
+        robot.mac_address = String::from("4a:00:11:22:33:44");
+        let mut mqtt = fake::settings().mqtt;
+
+        assert_eq!(robot_topic_id(&robot, "", &mqtt), "Living Room_2");
+        mqtt.topic_id = TopicId::Slug;
+        assert_eq!(robot_topic_id(&robot, "work-", &mqtt), "work-living-room-2");
+        mqtt.topic_id = TopicId::Serial;
+        assert_eq!(robot_topic_id(&robot, "", &mqtt), "OPS12345-4A0011223344");
+        mqtt.topic_id = TopicId::Mac;
+        assert_eq!(robot_topic_id(&robot, "", &mqtt), "4a:00:11:22:33:44");
+
+        // Aliases win over the setting
+        mqtt.aliases.insert(
+            String::from("OPS12345-4A0011223344"),
+            String::from("upstairs"),
+        );
+        assert_eq!(robot_topic_id(&robot, "", &mqtt), "upstairs");
+    }
+
+    #[test]
+    fn id_is_taken_from_its_own_level() {
+        let template = TopicTemplate::parse("home/devices/neato/{id}/set").unwrap();
+
+        assert_eq!(
+            template.subscription(&TopicValues::new()),
+            "home/devices/neato/+/set"
+        );
+        assert_eq!(
+            template.match_id("home/devices/neato/Vacuum/set", &TopicValues::new()),
+            Some(String::from("Vacuum"))
+        );
+        // Text of the template inside the id is kept
+        assert_eq!(
+            template.match_id("home/devices/neato/neato/set", &TopicValues::new()),
+            Some(String::from("neato"))
+        );
+        assert_eq!(
+            template.match_id("home/devices/neato/set", &TopicValues::new()),
+            None
+        );
+        assert_eq!(
+            template.match_id("home/devices/neato//set", &TopicValues::new()),
+            None
+        );
+        assert_eq!(
+            template.match_id("home/devices/other/Vacuum/set", &TopicValues::new()),
+            None
+        );
+    }
+
+    #[test]
+    fn id_can_share_a_level() {
+        let template = TopicTemplate::parse("neato/robot-{id}/cmd").unwrap();
+
+        assert_eq!(template.subscription(&TopicValues::new()), "neato/+/cmd");
+        assert_eq!(
+            template.match_id("neato/robot-Vacuum/cmd", &TopicValues::new()),
+            Some(String::from("Vacuum"))
+        );
+        assert_eq!(
+            template.match_id("neato/Vacuum/cmd", &TopicValues::new()),
+            None
+        );
+        assert!(TopicTemplate::parse("neato/{name}/set").is_err());
+    }
+
+    #[test]
+    fn known_values_are_part_of_the_match() {
+        let template = TopicTemplate::parse("{bridge_id}/{model}/{id}/set").unwrap();
+        let known = TopicValues::from([(Placeholder::BridgeId, String::from("upstairs"))]);
+
+        assert_eq!(template.subscription(&known), "upstairs/+/+/set");
+        let values = template
+            .match_values("upstairs/BotVacD7Connected/Vacuum/set", &known)
+            .unwrap();
+        assert_eq!(values[&Placeholder::Model], "BotVacD7Connected");
+        assert_eq!(values[&Placeholder::Id], "Vacuum");
+        // Another bridge on the same broker
+        assert_eq!(
+            template.match_id("downstairs/BotVacD7Connected/Vacuum/set", &known),
+            None
+        );
+        assert_eq!(
+            template.fill(&values).unwrap(),
+            "upstairs/BotVacD7Connected/Vacuum/set"
+        );
+    }
+
+    #[test]
+    fn placeholders_sharing_a_level_are_not_reversible() {
+        let known = TopicValues::from([(Placeholder::BridgeId, String::from("upstairs"))]);
+
+        let shared = TopicTemplate::parse("neato/{model}-{id}/set").unwrap();
+        assert!(shared.check_reversible(&known).is_err());
+
+        let with_bridge = TopicTemplate::parse("neato/{bridge_id}-{id}/set").unwrap();
+        assert!(with_bridge.check_reversible(&known).is_ok());
+        assert_eq!(
+            with_bridge.match_id("neato/upstairs-Vacuum/set", &known),
+            Some(String::from("Vacuum"))
+        );
+    }
+}
@@ -0,0 +1,665 @@
+use std::{
+    collections::HashMap,
+    fmt, fs,
+    path::{Path, PathBuf},
+};
+
+use croner::Cron;
+
+use crate::{
+    connection::MqttVersion,
+    mqtt,
+    rules::Condition,
+    settings::{
+        env_var_name, Backend, GroupSettings, GroupTable, MqttSettings, RuleSettings,
+        ScheduleSettings, Settings, SimulatorSettings,
+    },
+    topics::{bridge_values, Placeholder, TopicTemplate},
+};
+
+/// Keys that have no default and must be present in the settings
+const REQUIRED_KEYS: &[&str] = &["mqtt.id"];
+
+/// Keys that every entry of an array of tables must have
+const REQUIRED_ENTRY_KEYS: &[(&str, &[&str])] = &[
+    ("neato.accounts", &["email", "password"]),
+    ("rules", &["name", "when"]),
+    ("schedules", &["name", "robot", "cron"]),
+];
+
+#[derive(Debug)]
+pub struct Problem {
+    pub key: String,
+    pub message: String,
+    /// e.g. `Settings.toml:12`
+    pub location: Option<String>,
+}
+
+impl fmt::Display for Problem {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match &self.location {
+            Some(location) => write!(f, "{}: `{}`: {}", location, self.key, self.message),
+            None => write!(f, "`{}`: {}", self.key, self.message),
+        }
+    }
+}
+
+/// All problems found in the settings, reported at once
+#[derive(Debug)]
+pub struct ValidationErrors {
+    pub problems: Vec<Problem>,
+}
+
+impl fmt::Display for ValidationErrors {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        writeln!(
+            f,
+            "Found {} problem(s) in the settings:",
+            self.problems.len()
+        )?;
+        for problem in self.problems.iter() {
+            writeln!(f, "  - {}", problem)?;
+        }
+        Ok(())
+    }
+}
+
+impl std::error::Error for ValidationErrors {}
+
+/// Finds the TOML file `config::File::with_name` would pick up for `path`
+pub fn settings_file(path: &str) -> Option<PathBuf> {
+    let path = Path::new(path);
+    if path.extension().is_some() {
+        return path.is_file().then(|| path.to_path_buf());
+    }
+    let with_extension = path.with_extension("toml");
+    with_extension.is_file().then_some(with_extension)
+}
+
+/// Line of `key` (e.g. `neato.accounts.1.email`) in a TOML file
+fn find_line(contents: &str, key: &str) -> Option<usize> {
+    let parts: Vec<&str> = key.split('.').collect();
+    let (name, path) = parts.split_last()?;
+    // Array indices are tracked by counting `[[table]]` headers
+    let section: Vec<&str> = path
+        .iter()
+        .filter(|p| p.parse::<usize>().is_err())
+        .copied()
+        .collect();
+    let section = section.join(".");
+    let index = path.iter().find_map(|p| p.parse::<usize>().ok());
+
+    let mut current_section = String::new();
+    let mut current_index: Option<usize> = None;
+    // Number of entries seen so far, per array
+    let mut counts: HashMap<String, usize> = HashMap::new();
+    for (line_number, line) in contents.lines().enumerate() {
+        let line = line.trim();
+        if let Some(header) = line.strip_prefix("[[") {
+            current_section = header.trim_end_matches("]]").trim().to_string();
+            let count = counts.entry(current_section.clone()).or_default();
+            current_index = Some(*count);
+            *count += 1;
+            continue;
+        }
+        if let Some(header) = line.strip_prefix('[') {
+            current_section = header.trim_end_matches(']').trim().to_string();
+            current_index = None;
+            continue;
+        }
+        if current_section != section || (index.is_some() && current_index != index) {
+            continue;
+        }
+        if let Some((line_key, _)) = line.split_once('=') {
+            if line_key.trim().trim_matches('"') == *name {
+                return Some(line_number + 1);
+            }
+        }
+    }
+
+    None
+}
+
+struct Validator<'a> {
+    file: Option<(&'a Path, String)>,
+    /// Already reported as missing, further problems with these keys are ignored
+    missing: Vec<String>,
+    problems: Vec<Problem>,
+}
+
+impl<'a> Validator<'a> {
+    fn report_missing(&mut self, key: &str) {
+        self.report(key, "missing required setting");
+        self.missing.push(key.to_string());
+    }
+
+    fn report(&mut self, key: &str, message: impl Into<String>) {
+        if self.missing.iter().any(|k| k == key) {
+            return;
+        }
+        let env_var = env_var_name(key);
+        let location = if std::env::var_os(&env_var).is_some() {
+            Some(format!("${}", env_var))
+        } else if std::env::var_os(format!("{}_FILE", env_var)).is_some() {
+            Some(format!("${}_FILE", env_var))
+        } else {
+            self.file.as_ref().and_then(|(path, contents)| {
+                find_line(contents, key).map(|line| format!("{}:{}", path.display(), line))
+            })
+        };
+        self.problems.push(Problem {
+            key: key.to_string(),
+            message: message.into(),
+            location,
+        });
+    }
+
+    fn check_topic_template(&mut self, key: &str, template: &str, id_required: bool) {
+        match template.matches("{id}").count() {
+            0 if !id_required => {}
+            1 => {}
+            _ if id_required => self.report(key, "must contain `{id}` exactly once"),
+            _ => self.report(key, "must not contain `{id}` more than once"),
+        }
+        if template.contains('+') || template.contains('#') {
+            self.report(key, "must not contain the MQTT wildcards `+` or `#`");
+        }
+        if template.starts_with('/') || template.ends_with('/') || template.contains("//") {
+            self.report(key, "must not have empty topic levels");
+        }
+        if let Err(e) = TopicTemplate::parse(template) {
+            self.report(key, e);
+        }
+    }
+
+    fn check_mqtt(&mut self, mqtt: &MqttSettings) {
+        if mqtt.id.trim().is_empty() {
+            self.report("mqtt.id", "must not be empty");
+        }
+        if mqtt.host.trim().is_empty() {
+            self.report("mqtt.host", "must not be empty");
+        }
+        if mqtt.port == 0 {
+            self.report("mqtt.port", "must be between 1 and 65535");
+        }
+        if mqtt.version == MqttVersion::V5 && mqtt.keep_alive < 5 {
+            self.report("mqtt.keep_alive", "must be at least 5 seconds with MQTT 5");
+        }
+        if mqtt.inflight == 0 {
+            self.report("mqtt.inflight", "must be at least 1");
+        }
+        self.check_topic_template("mqtt.topic", &mqtt.topic, true);
+        self.check_topic_template("mqtt.set_topic", &mqtt.set_topic, true);
+        if mqtt.topic == mqtt.set_topic {
+            self.report("mqtt.set_topic", "must differ from `mqtt.topic`");
+        }
+        let known = bridge_values(mqtt);
+        if let Ok(set_topic) = TopicTemplate::parse(&mqtt.set_topic) {
+            if let Err(e) = set_topic.check_reversible(&known) {
+                self.report("mqtt.set_topic", e);
+            }
+        }
+        let robot_placeholders = |template: &str| {
+            TopicTemplate::parse(template).is_ok_and(|template| {
+                template
+                    .placeholders()
+                    .any(|p| p != Placeholder::Id && p != Placeholder::BridgeId)
+            })
+        };
+        match &mqtt.broadcast_topic {
+            Some(broadcast) => {
+                self.check_topic_template("mqtt.broadcast_topic", broadcast, false);
+                if broadcast.contains("{id}") || robot_placeholders(broadcast) {
+                    self.report(
+                        "mqtt.broadcast_topic",
+                        "must not contain placeholders other than `{bridge_id}`",
+                    );
+                }
+            }
+            None if robot_placeholders(&mqtt.topic) => self.report(
+                "mqtt.broadcast_topic",
+                "must be set when `mqtt.topic` has placeholders other than `{id}` and `{bridge_id}`",
+            ),
+            None => {}
+        }
+        let hides_robot = TopicTemplate::parse(&mqtt.set_topic).is_ok_and(|set_topic| {
+            set_topic
+                .match_id(&mqtt.get_broadcast_topic(), &known)
+                .is_some()
+        });
+        if hides_robot {
+            self.report(
+                "mqtt.broadcast_topic",
+                "must not match `mqtt.set_topic`, that robot could not be addressed",
+            );
+        }
+        for (robot, alias) in mqtt.aliases.iter() {
+            if alias.is_empty() || alias.contains(['/', '+', '#']) {
+                self.report(
+                    &format!("mqtt.aliases.{}", robot),
+                    "must be non-empty and must not contain `/`, `+` or `#`",
+                );
+            }
+        }
+    }
+
+    fn check_simulator(&mut self, simulator: &SimulatorSettings) {
+        if simulator.robots.is_empty() {
+            self.report("simulator.robots", "must list at least one robot");
+        }
+        if simulator.battery_drain < 0.0 {
+            self.report("simulator.battery_drain", "must not be negative");
+        }
+        if simulator.charge_rate < 0.0 {
+            self.report("simulator.charge_rate", "must not be negative");
+        }
+        if !(0.0..=1.0).contains(&simulator.alert_probability) {
+            self.report("simulator.alert_probability", "must be between 0 and 1");
+        }
+        if simulator.time_scale <= 0.0 {
+            self.report("simulator.time_scale", "must be greater than 0");
+        }
+    }
+
+    fn check_rules(&mut self, rules: &[RuleSettings]) {
+        let mut names = Vec::new();
+        for (i, rule) in rules.iter().enumerate() {
+            if rule.name.trim().is_empty() {
+                self.report(&format!("rules.{}.name", i), "must not be empty");
+            }
+            if names.contains(&rule.name) {
+                self.report(
+                    &format!("rules.{}.name", i),
+                    format!("rule name \"{}\" is used more than once", rule.name),
+                );
+            }
+            names.push(rule.name.clone());
+            if let Err(err) = rule.when.parse::<Condition>() {
+                self.report(&format!("rules.{}.when", i), err.to_string());
+            }
+        }
+    }
+
+    fn check_schedules(&mut self, schedules: &[ScheduleSettings]) {
+        let mut names = Vec::new();
+        for (i, schedule) in schedules.iter().enumerate() {
+            let key = |name: &str| format!("schedules.{}.{}", i, name);
+            if schedule.name.trim().is_empty() || schedule.name.contains(['/', '+', '#']) {
+                self.report(
+                    &key("name"),
+                    "must be non-empty and must not contain `/`, `+` or `#`",
+                );
+            }
+            if names.contains(&schedule.name) {
+                self.report(
+                    &key("name"),
+                    format!("schedule name \"{}\" is used more than once", schedule.name),
+                );
+            }
+            names.push(schedule.name.clone());
+            if schedule.robot.trim().is_empty() {
+                self.report(&key("robot"), "must not be empty");
+            }
+            if let Err(err) = Cron::new(&schedule.cron).parse() {
+                self.report(&key("cron"), format!("invalid cron expression: {}", err));
+            }
+            if let Some(topic) = &schedule.condition_topic {
+                if topic.is_empty()
+                    || topic.contains(['+', '#'])
+                    || topic.starts_with('/')
+                    || topic.ends_with('/')
+                    || topic.contains("//")
+                {
+                    self.report(
+                        &key("condition_topic"),
+                        "must be a topic without wildcards or empty topic levels",
+                    );
+                }
+            }
+            if schedule.min_charge.is_some_and(|c| !(0..=100).contains(&c)) {
+                self.report(&key("min_charge"), "must be between 0 and 100");
+            }
+            if schedule.defer == Some(0) {
+                self.report(&key("defer"), "must be at least 1 minute");
+            }
+        }
+    }
+
+    fn check_groups(&mut self, groups: &HashMap<String, GroupSettings>) {
+        for (name, group) in groups.iter() {
+            let key = format!("groups.{}", name);
+            if name == "set" || name.contains(['/', '+', '#']) {
+                self.report(&key, "name must not be `set` or contain `/`, `+` or `#`");
+            }
+            if group.robots().is_empty() {
+                self.report(&key, "must list at least one robot");
+            }
+            if let GroupSettings::Table(GroupTable {
+                start_timeout: Some(0),
+                ..
+            }) = group
+            {
+                self.report(
+                    &format!("{}.start_timeout", key),
+                    "must be at least 1 minute",
+                );
+            }
+            let mut robots = group.robots().to_vec();
+            robots.sort();
+            for pair in robots.windows(2).filter(|pair| pair[0] == pair[1]) {
+                self.report(
+                    &key,
+                    format!("robot \"{}\" is listed more than once", pair[0]),
+                );
+            }
+        }
+    }
+
+    fn check(&mut self, settings: &Settings) {
+        self.check_mqtt(&settings.mqtt);
+        // The subscriptions made on connect should fit in the queue of the connection
+        let subscriptions = mqtt::subscriptions(settings).len();
+        if settings.mqtt.channel_capacity < subscriptions {
+            self.report(
+                "mqtt.channel_capacity",
+                format!(
+                    "must be at least {}, the number of subscribed topics",
+                    subscriptions
+                ),
+            );
+        }
+        self.check_topic_template("notifications.topic", &settings.notifications.topic, false);
+        self.check_rules(&settings.rules);
+        self.check_schedules(&settings.schedules);
+        self.check_groups(&settings.groups);
+        if settings.neato.backend == Backend::Simulator {
+            self.check_simulator(&settings.simulator);
+        }
+
+        let neato = &settings.neato;
+        if neato.email.is_some() != neato.password.is_some() {
+            let key = if neato.email.is_some() {
+                "neato.password"
+            } else {
+                "neato.email"
+            };
+            self.report(
+                key,
+                "`neato.email` and `neato.password` must be set together",
+            );
+        }
+        if neato.accounts().is_empty() {
+            self.report(
+                "neato.email",
+                "no Neato account configured, set `neato.email` and `neato.password` or add `[[neato.accounts]]`",
+            );
+        }
+        let mut names = Vec::new();
+        for (i, account) in neato.accounts.iter().enumerate() {
+            if account.email.trim().is_empty() {
+                self.report(&format!("neato.accounts.{}.email", i), "must not be empty");
+            }
+            if account.password.expose().is_empty() {
+                self.report(
+                    &format!("neato.accounts.{}.password", i),
+                    "must not be empty",
+                );
+            }
+            if account.topic_prefix.contains(['/', '+', '#']) {
+                self.report(
+                    &format!("neato.accounts.{}.topic_prefix", i),
+                    "must not contain `/`, `+` or `#`",
+                );
+            }
+            names.push(account.name().to_string());
+        }
+        if let Some(email) = &neato.email {
+            names.push(email.clone());
+        }
+        names.sort();
+        for pair in names.windows(2).filter(|pair| pair[0] == pair[1]) {
+            self.report(
+                "neato.accounts",
+                format!("account name \"{}\" is used more than once", pair[0]),
+            );
+        }
+        if neato.request_timeout == 0 {
+            self.report("neato.request_timeout", "must be at least 1 second");
+        }
+        if neato.max_concurrent_requests == 0 {
+            self.report("neato.max_concurrent_requests", "must be at least 1");
+        }
+        if neato.poll_interval == 0 {
+            self.report("neato.poll_interval", "must be at least 1 second");
+        }
+        if neato.offline_poll_interval == 0 {
+            self.report("neato.offline_poll_interval", "must be at least 1 second");
+        }
+
+        if !(0..=100).contains(&settings.events.battery_threshold) {
+            self.report("events.battery_threshold", "must be between 0 and 100");
+        }
+
+        if settings.http.enabled && settings.http.port == 0 {
+            self.report("http.port", "must be between 1 and 65535");
+        }
+    }
+}
+
+/// Deserializes the settings, collecting missing and unknown keys, invalid values and
+/// inconsistent options instead of stopping at the first problem. Rules, cron expressions and
+/// topic templates are parsed here, so the code using them can skip values that don't parse.
+pub fn validate(config: config::Config, file: Option<&Path>) -> Result<Settings, ValidationErrors> {
+    let mut validator = Validator {
+        file: file.and_then(|path| fs::read_to_string(path).ok().map(|c| (path, c))),
+        missing: Vec::new(),
+        problems: Vec::new(),
+    };
+
+    let mut missing = Vec::new();
+    for key in REQUIRED_KEYS {
+        if config.get::<config::Value>(key).is_err() {
+            missing.push(key.to_string());
+        }
+    }
+    for (array, keys) in REQUIRED_ENTRY_KEYS {
+        for (i, entry) in config
+            .get_array(array)
+            .unwrap_or_default()
+            .into_iter()
+            .enumerate()
+        {
+            let entry = entry.into_table().unwrap_or_default();
+            for key in keys.iter() {
+                if !entry.contains_key(*key) {
+                    missing.push(format!("{}.{}.{}", array, i, key));
+                }
+            }
+        }
+    }
+    // Fill in missing keys so that the remaining settings can still be checked
+    let mut builder = config::Config::builder().add_source(config);
+    for key in missing.iter() {
+        validator.report_missing(key);
+        // `config` addresses array elements as `accounts[0]`
+        let config_key = key
+            .split('.')
+            .map(|part| match part.parse::<usize>() {
+                Ok(i) => format!("[{}]", i),
+                Err(_) => format!(".{}", part),
+            })
+            .collect::<String>();
+        builder = builder
+            .set_override(config_key.trim_start_matches('.'), "")
+            .expect("valid settings key");
+    }
+    let config = match builder.build() {
+        Ok(config) => config,
+        Err(err) => {
+            validator.report("", err.to_string());
+            return Err(ValidationErrors {
+                problems: validator.problems,
+            });
+        }
+    };
+
+    let mut unknown_keys = Vec::new();
+    let mut on_unknown_key = |path: serde_ignored::Path| unknown_keys.push(path.to_string());
+    let deserializer = serde_ignored::Deserializer::new(config, &mut on_unknown_key);
+    let result: Result<Settings, _> = serde_path_to_error::deserialize(deserializer);
+
+    for key in unknown_keys {
+        validator.report(&key, "unknown setting");
+    }
+
+    let settings = match result {
+        Ok(settings) => {
+            validator.check(&settings);
+            Some(settings)
+        }
+        Err(err) => {
+            validator.report(&err.path().to_string(), err.into_inner().to_string());
+            None
+        }
+    };
+
+    match settings {
+        Some(settings) if validator.problems.is_empty() => Ok(settings),
+        _ => Err(ValidationErrors {
+            problems: validator.problems,
+        }),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::settings::{read_settings, tests::ScopedEnv};
+
+    const VALID: &str = r#"[mqtt]
+id = "neato-mqtt"
+
+[neato]
+email = "test@example.com"
+password = "password"
+"#;
+
+    /// Problems found in `contents`, read from a settings file named after the test
+    fn problems(name: &str, contents: &str) -> Vec<Problem> {
+        let path =
+            std::env::temp_dir().join(format!("neato-mqtt-{}-{}.toml", name, std::process::id()));
+        fs::write(&path, contents).unwrap();
+        let result = {
+            let _env = ScopedEnv::new(&[]);
+            read_settings(path.to_str())
+        };
+        fs::remove_file(&path).unwrap();
+        match result {
+            Ok(_) => Vec::new(),
+            Err(err) => err.downcast::<ValidationErrors>().unwrap().problems,
+        }
+    }
+
+    fn find<'a>(problems: &'a [Problem], key: &str) -> &'a Problem {
+        problems
+            .iter()
+            .find(|p| p.key == key)
+            .unwrap_or_else(|| panic!("no problem with `{}` in {:?}", key, problems))
+    }
+
+    #[test]
+    fn valid_settings_have_no_problems() {
+        assert!(problems("valid", VALID).is_empty());
+    }
+
+    #[test]
+    fn unknown_keys_are_reported_with_their_line() {
+        let contents = VALID.replace("[neato]\n", "[neato]\npasword = \"typo\"\n");
+
+        let problems = problems("unknown", &contents);
+
+        let problem = find(&problems, "neato.pasword");
+        assert_eq!(problem.message, "unknown setting");
+        assert!(problem.location.as_ref().unwrap().ends_with(".toml:5"));
+    }
+
+    #[test]
+    fn missing_values_are_reported() {
+        let contents = VALID.replace("id = \"neato-mqtt\"\n", "");
+
+        let problems = problems("missing", &contents);
+
+        assert_eq!(
+            find(&problems, "mqtt.id").message,
+            "missing required setting"
+        );
+    }
+
+    #[test]
+    fn invalid_topic_templates_are_reported() {
+        let without_id = VALID.replace("[neato]", "set_topic = \"neato/set\"\n\n[neato]");
+        let found = problems("without-id", &without_id);
+        let problem = find(&found, "mqtt.set_topic");
+        assert_eq!(problem.message, "must contain `{id}` exactly once");
+        assert!(problem.location.as_ref().unwrap().ends_with(".toml:4"));
+
+        let irreversible = VALID.replace(
+            "[neato]",
+            "set_topic = \"neato/{model}-{id}/set\"\n\n[neato]",
+        );
+        let found = problems("irreversible", &irreversible);
+        assert!(find(&found, "mqtt.set_topic")
+            .message
+            .contains("must not share a topic level"));
+    }
+
+    #[test]
+    fn lines_of_array_entries_are_found() {
+        let contents = r#"[[neato.accounts]]
+email = "first@example.com"
+
+[[neato.accounts]]
+name = "second"
+email = "second@example.com"
+"#;
+
+        assert_eq!(find_line(contents, "neato.accounts.0.email"), Some(2));
+        assert_eq!(find_line(contents, "neato.accounts.1.email"), Some(6));
+        assert_eq!(find_line(contents, "neato.accounts.2.email"), None);
+    }
+
+    #[test]
+    fn entries_of_different_arrays_are_counted_separately() {
+        let contents = format!(
+            r#"{}
+[[rules]]
+name = "stranded"
+when = "charge < 15"
+
+[[schedules]]
+name = "weekdays"
+robot = "Vacuum"
+cron = "not a cron expression"
+"#,
+            VALID
+        );
+
+        let found = problems("arrays", &contents);
+
+        let problem = find(&found, "schedules.0.cron");
+        assert!(problem.location.as_ref().unwrap().ends_with(".toml:15"));
+        assert_eq!(find_line(&contents, "rules.0.when"), Some(10));
+    }
+
+    #[test]
+    fn channel_capacity_must_fit_the_subscriptions() {
+        let contents = VALID.replace("[neato]", "channel_capacity = 3\n\n[neato]");
+
+        let found = problems("capacity", &contents);
+
+        assert_eq!(
+            find(&found, "mqtt.channel_capacity").message,
+            "must be at least 4, the number of subscribed topics"
+        );
+    }
+}